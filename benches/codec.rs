@@ -0,0 +1,45 @@
+use criterion::{criterion_group, criterion_main, Criterion, black_box};
+
+use queen_core::queen::net::{Codec, NsonCodec};
+use queen_core::queen::nson::Message;
+use queen_core::codec::{JsonCodec, MessagePackCodec};
+
+fn message(fields: usize) -> Message {
+    let mut message = Message::new();
+
+    for i in 0..fields {
+        if i % 2 == 0 {
+            message.insert(format!("field{}", i), i as i64);
+        } else {
+            message.insert(format!("field{}", i), format!("value{}", i));
+        }
+    }
+
+    message
+}
+
+fn bench<C: Codec>(c: &mut Criterion, name: &str, message: &Message) {
+    let mut codec = C::new();
+    let bytes = codec.encode(&None, message.clone()).unwrap();
+
+    c.bench_function(&format!("{}_encode", name), |b| {
+        b.iter(|| codec.encode(&None, black_box(message.clone())).unwrap())
+    });
+
+    c.bench_function(&format!("{}_decode", name), |b| {
+        b.iter(|| codec.decode(&None, black_box(bytes.clone())).unwrap())
+    });
+}
+
+fn codec_json_vs_msgpack(c: &mut Criterion) {
+    for fields in [50, 1000].iter() {
+        let message = message(*fields);
+
+        bench::<NsonCodec>(c, &format!("nson_{}", fields), &message);
+        bench::<JsonCodec>(c, &format!("json_{}", fields), &message);
+        bench::<MessagePackCodec>(c, &format!("msgpack_{}", fields), &message);
+    }
+}
+
+criterion_group!(benches, codec_json_vs_msgpack);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+use criterion::{criterion_group, criterion_main, Criterion, BenchmarkId, black_box};
+
+use queen_core::packet::{Packet, Compress, MTU};
+
+fn packet(compress: Compress, size: usize) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.set_compress(compress);
+    packet.chan = "bench".to_string();
+    packet.body = b"queen-core packet body ".iter().cycle().take(size).cloned().collect();
+
+    packet
+}
+
+fn packet_to_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet_to_bytes");
+
+    for size in [100, 1024, MTU as usize].iter() {
+        for compress in [Compress::None, Compress::Zstd].iter() {
+            let packet = packet(*compress, *size);
+
+            group.bench_with_input(BenchmarkId::new(format!("{:?}", compress), size), &packet, |b, packet| {
+                b.iter(|| black_box(packet).to_bytes(None).unwrap())
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn packet_from_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet_from_bytes");
+
+    for size in [100, 1024, MTU as usize].iter() {
+        for compress in [Compress::None, Compress::Zstd].iter() {
+            let bytes = packet(*compress, *size).to_bytes(None).unwrap();
+
+            group.bench_with_input(BenchmarkId::new(format!("{:?}", compress), size), &bytes, |b, bytes| {
+                b.iter(|| Packet::from_bytes(black_box(bytes), true, None).unwrap())
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, packet_to_bytes, packet_from_bytes);
+criterion_main!(benches);
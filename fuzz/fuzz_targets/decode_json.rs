@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use queen_core::codec::decode_json;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_json(data);
+});
@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use queen_core::packet::Packet;
+use queen_core::crypto::{PacketCrypto, Key, Key128};
+
+fuzz_target!(|data: &[u8]| {
+    let crypto = PacketCrypto::new(0, Key::Aes128Gcm(Key128([7; 16]))).unwrap();
+
+    let _ = Packet::from_bytes(data, false, None);
+    let _ = Packet::from_bytes(data, true, Some(&crypto));
+});
@@ -1,6 +1,6 @@
 use std::io::{self, Write, Read, Cursor};
 
-use serde_json::{Value, to_writer, from_reader};
+use serde_json::{Value, to_writer, from_slice};
 
 use queen::net::Codec;
 use queen::crypto::Crypto;
@@ -14,31 +14,81 @@ impl Codec for JsonCodec {
         JsonCodec
     }
 
-    fn decode(&mut self, crypto: &Option<Crypto>, mut bytes: Vec<u8>) -> Result<Message> {
-        if let Some(crypto) = crypto {
-            crypto.decrypt(&mut bytes).map_err(|err|
-                Error::InvalidData(format!("{}", err))
-            )?;
-        }
+    fn decode(&mut self, crypto: &Option<Crypto>, bytes: Vec<u8>) -> Result<Message> {
+        decode_with(crypto, bytes, |bytes| Ok(decode_json(bytes)?.into()))
+    }
+
+    fn encode(&mut self, crypto: &Option<Crypto>, message: Message) -> Result<Vec<u8>> {
+        encode_with(crypto, message, |message| encode_json(&message.into()))
+    }
+}
+
+#[cfg(feature = "msgpack")]
+pub struct MessagePackCodec;
 
-        let json = decode_json(&bytes)?;
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePackCodec {
+    fn new() -> Self {
+        MessagePackCodec
+    }
 
-        Ok(json.into())
+    fn decode(&mut self, crypto: &Option<Crypto>, bytes: Vec<u8>) -> Result<Message> {
+        decode_with(crypto, bytes, decode_msgpack)
     }
 
     fn encode(&mut self, crypto: &Option<Crypto>, message: Message) -> Result<Vec<u8>> {
-        let json: serde_json::Value = message.into();
+        encode_with(crypto, message, |message| encode_msgpack(&message))
+    }
+}
 
-        let mut bytes = encode_json(&json)?;
+#[cfg(feature = "cbor")]
+pub struct CborCodec;
 
-        if let Some(crypto) = crypto {
-            crypto.encrypt(&mut bytes).map_err(|err|
-                Error::InvalidData(format!("{}", err))
-            )?;
-        }
+#[cfg(feature = "cbor")]
+impl Codec for CborCodec {
+    fn new() -> Self {
+        CborCodec
+    }
 
-        Ok(bytes)
+    fn decode(&mut self, crypto: &Option<Crypto>, bytes: Vec<u8>) -> Result<Message> {
+        decode_with(crypto, bytes, decode_cbor)
     }
+
+    fn encode(&mut self, crypto: &Option<Crypto>, message: Message) -> Result<Vec<u8>> {
+        encode_with(crypto, message, |message| encode_cbor(&message))
+    }
+}
+
+// decrypts `bytes` in place, if needed, before handing them to `decode`
+fn decode_with(
+    crypto: &Option<Crypto>,
+    mut bytes: Vec<u8>,
+    decode: impl FnOnce(&[u8]) -> io::Result<Message>
+) -> Result<Message> {
+    if let Some(crypto) = crypto {
+        crypto.decrypt(&mut bytes).map_err(|err|
+            Error::InvalidData(format!("{}", err))
+        )?;
+    }
+
+    Ok(decode(&bytes)?)
+}
+
+// encrypts the output of `encode` in place, if needed
+fn encode_with(
+    crypto: &Option<Crypto>,
+    message: Message,
+    encode: impl FnOnce(Message) -> io::Result<Vec<u8>>
+) -> Result<Vec<u8>> {
+    let mut bytes = encode(message)?;
+
+    if let Some(crypto) = crypto {
+        crypto.encrypt(&mut bytes).map_err(|err|
+            Error::InvalidData(format!("{}", err))
+        )?;
+    }
+
+    Ok(bytes)
 }
 
 #[inline]
@@ -59,20 +109,89 @@ pub fn encode_json(json: &Value) -> io::Result<Vec<u8>> {
 
     to_writer(&mut buf, json)?;
 
-    let len_bytes = (buf.len() as u32).to_le_bytes();
-    buf[..4].clone_from_slice(&len_bytes);
+    fill_len_prefix(&mut buf);
 
     Ok(buf)
 }
 
 pub fn decode_json(slice: &[u8]) -> io::Result<Value> {
+    let payload = strip_len_prefix(slice)?;
+
+    let json: Value = from_slice(payload)?;
+
+    Ok(json)
+}
+
+#[cfg(feature = "msgpack")]
+pub fn encode_msgpack(message: &Message) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(64);
+    write_u32(&mut buf, 0)?;
+
+    rmp_serde::encode::write(&mut buf, message).map_err(|err|
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    )?;
+
+    fill_len_prefix(&mut buf);
+
+    Ok(buf)
+}
+
+#[cfg(feature = "msgpack")]
+pub fn decode_msgpack(slice: &[u8]) -> io::Result<Message> {
+    let payload = strip_len_prefix(slice)?;
+
+    let message: Message = rmp_serde::from_slice(payload).map_err(|err|
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    )?;
+
+    Ok(message)
+}
+
+#[cfg(feature = "cbor")]
+pub fn encode_cbor(message: &Message) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(64);
+    write_u32(&mut buf, 0)?;
+
+    ciborium::ser::into_writer(message, &mut buf).map_err(|err|
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}", err))
+    )?;
+
+    fill_len_prefix(&mut buf);
+
+    Ok(buf)
+}
+
+#[cfg(feature = "cbor")]
+pub fn decode_cbor(slice: &[u8]) -> io::Result<Message> {
+    let payload = strip_len_prefix(slice)?;
+
+    let message: Message = ciborium::de::from_reader(payload).map_err(|err|
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}", err))
+    )?;
+
+    Ok(message)
+}
+
+// the length prefix covers the payload only, not itself
+fn fill_len_prefix(buf: &mut [u8]) {
+    let len_bytes = ((buf.len() - 4) as u32).to_le_bytes();
+    buf[..4].clone_from_slice(&len_bytes);
+}
+
+fn strip_len_prefix(slice: &[u8]) -> io::Result<&[u8]> {
     let mut reader = Cursor::new(slice);
 
-    read_u32(&mut reader)?;
+    let len = read_u32(&mut reader)? as usize;
 
-    let json: Value = from_reader(&mut reader)?;
+    let payload = &slice[4..];
+    if payload.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("length prefix {} does not match payload length {}", len, payload.len())
+        ))
+    }
 
-    Ok(json)
+    Ok(payload)
 }
 
 #[cfg(test)]
@@ -95,4 +214,106 @@ mod test {
 
         assert!(json == json2);
     }
+
+    #[test]
+    fn length_prefix_excludes_itself() {
+        let json = json!({});
+
+        let data = encode_json(&json).unwrap();
+        assert_eq!(data, vec![2, 0, 0, 0, b'{', b'}']);
+
+        assert!(decode_json(&data).unwrap() == json);
+    }
+
+    #[test]
+    fn encode_and_decode_nested() {
+        let mut json = json!("leaf");
+        // each level nests an object and an array; stay under serde_json's recursion limit of 128
+        for i in 0..32 {
+            json = json!({ "level": i, "child": [json] });
+        }
+
+        let data = encode_json(&json).unwrap();
+
+        assert!(decode_json(&data).unwrap() == json);
+    }
+
+    #[test]
+    fn encode_and_decode_unicode() {
+        let json = json!({
+            "中文": "你好，世界",
+            "emoji": "🦀🚀✨",
+            "mixed": ["Ünïcödé", "Ελληνικά", "\u{0}\u{ffff}"]
+        });
+
+        let data = encode_json(&json).unwrap();
+
+        assert!(decode_json(&data).unwrap() == json);
+    }
+
+    #[test]
+    fn decode_invalid_length() {
+        assert!(decode_json(&[]).is_err());
+        assert!(decode_json(&[3, 0, 0, 0, b'{', b'}']).is_err());
+        assert!(decode_json(&[1, 0, 0, 0, b'{', b'}']).is_err());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_encode_and_decode() {
+        use queen::nson::{msg, Value, MessageId};
+
+        use super::{encode_msgpack, decode_msgpack};
+
+        let message = msg!{
+            "a": i64::MIN,
+            "b": "456",
+            "c": [u64::MAX, -1.5, Value::Null, true],
+            "d": { "e": vec![0u8, 1, 2, 255] },
+            "f": MessageId::new()
+        };
+
+        let data = encode_msgpack(&message).unwrap();
+        assert_eq!(data.len() - 4, u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize);
+
+        let message2 = decode_msgpack(&data).unwrap();
+
+        assert_eq!(message, message2);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_encode_and_decode() {
+        use queen::net::Codec;
+        use queen::nson::{msg, Value, MessageId};
+
+        use super::{CborCodec, encode_cbor, decode_cbor};
+
+        // {"a": 1}
+        let data = encode_cbor(&msg!{"a": 1}).unwrap();
+        assert_eq!(data, vec![4, 0, 0, 0, 0xa1, 0x61, b'a', 0x01]);
+
+        // {"b": h'00ff'}
+        let data = encode_cbor(&msg!{"b": vec![0u8, 255]}).unwrap();
+        assert_eq!(data, vec![6, 0, 0, 0, 0xa1, 0x61, b'b', 0x42, 0x00, 0xff]);
+
+        let message = msg!{
+            "a": i64::MIN,
+            "b": "hello 世界",
+            "c": [u64::MAX, -0.5, Value::Null, false],
+            "d": { "e": vec![0u8, 1, 127, 255] },
+            "f": MessageId::new()
+        };
+
+        let data = encode_cbor(&message).unwrap();
+        assert_eq!(data.len() - 4, u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize);
+
+        let message2 = decode_cbor(&data).unwrap();
+
+        assert_eq!(message, message2);
+
+        let mut codec = CborCodec::new();
+        let bytes = codec.encode(&None, message.clone()).unwrap();
+        assert_eq!(codec.decode(&None, bytes).unwrap(), message);
+    }
 }
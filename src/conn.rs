@@ -1,45 +1,246 @@
+use std::io::{self, Write};
 use std::net::SocketAddr;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::thread;
+use std::collections::{VecDeque, HashMap};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use rand::Rng;
 
 use queen::{Wire, Port, Socket};
 use queen::error::{Result, Error, RecvError, SendError};
-use queen::net::{NsonCodec, CryptoOptions};
+use queen::net::{Codec, NsonCodec, CryptoOptions};
 use queen::nson::{Message, MessageId};
+use queen::dict::{ID, CHAN, PING};
 
 pub struct Conn {
-    connector: Box<dyn Connector>,
-    wire: Option<Wire<Message>>
+    connector: Arc<Mutex<Box<dyn Connector>>>,
+    wire: Option<Wire<Message>>,
+    // messages read by `send_with_ack` that were not the awaited reply
+    pending: VecDeque<Message>,
+    max_pending: usize,
+    reconnect: Option<ReconnectConfig>,
+    reconnecting: Option<Reconnecting>,
+    reconnect_stats: Arc<Mutex<ReconnectStats>>,
+    last_ping_sent: Option<Instant>,
+    heartbeat_fn: Option<Box<dyn Fn(Duration) + Send + 'static>>
+}
+
+pub const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
+pub const DEFAULT_MAX_PENDING: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// `None` retries forever, defaults to `DEFAULT_RECONNECT_ATTEMPTS`
+    pub max_attempts: Option<u32>,
+    pub base_delay: Duration,
+    pub max_delay: Duration
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            max_attempts: Some(DEFAULT_RECONNECT_ATTEMPTS),
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReconnectStats {
+    /// attempts made by the last `connect_with_retry` call or reconnect
+    pub attempts: u32,
+    pub last_error: Option<String>
 }
 
 pub trait Connector: Send + 'static {
     fn connect(&self) -> Result<Wire<Message>>;
 }
 
+// a background reconnect, cancelled when dropped
+struct Reconnecting {
+    rx: mpsc::Receiver<Result<Wire<Message>>>,
+    cancel: Arc<AtomicBool>
+}
+
+impl Drop for Reconnecting {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+// how long an operation waits for a background reconnect
+#[derive(Clone, Copy)]
+enum Block {
+    No,
+    Until(Instant),
+    Forever
+}
+
 impl Conn {
     pub fn new(connector: impl Connector) -> Self {
-        let conn = Conn {
-            connector: Box::new(connector),
-            wire: None
-        };
-
-        conn
+        Conn {
+            connector: Arc::new(Mutex::new(Box::new(connector))),
+            wire: None,
+            pending: VecDeque::new(),
+            max_pending: DEFAULT_MAX_PENDING,
+            reconnect: None,
+            reconnecting: None,
+            reconnect_stats: Arc::new(Mutex::new(ReconnectStats::default())),
+            last_ping_sent: None,
+            heartbeat_fn: None
+        }
     }
 
     pub fn connected(&self) -> bool {
-        !self.wire.is_none()
+        self.wire.is_some()
     }
 
     pub fn connect(&mut self) -> Result<()> {
         log::debug!("conn::connect");
-        let wire = self.connector.connect()?;
+        self.reconnecting = None;
+
+        let wire = self.connector.lock().unwrap().connect()?;
+
+        self.wire = Some(wire);
+
+        Ok(())
+    }
+
+    pub fn connect_with_retry(
+        &mut self,
+        max_attempts: Option<u32>,
+        base_delay: Duration,
+        max_delay: Duration
+    ) -> Result<()> {
+        self.reconnecting = None;
+
+        let config = ReconnectConfig {
+            max_attempts,
+            base_delay,
+            max_delay
+        };
+
+        let wire = retry(&self.connector, &self.reconnect_stats, &config, &AtomicBool::new(false))?;
 
         self.wire = Some(wire);
 
         Ok(())
     }
 
+    /// Reconnect with `connect_with_retry` on a background thread whenever
+    /// the wire is found disconnected.
+    ///
+    /// While the reconnect runs, `recv` returns `Ok(None)` and `send` fails
+    /// with `Error::Disconnected` instead of blocking. `wait` and
+    /// `send_with_ack` wait for it up to their timeout. Once
+    /// `config.max_attempts` is exhausted, the last connect error is
+    /// returned by the next call, and the call after that starts over.
+    pub fn auto_reconnect(&mut self, config: ReconnectConfig) {
+        self.reconnect = Some(config);
+    }
+
+    /// Called with the round-trip time when the reply to a `PING` sent
+    /// through this `Conn` is received.
+    pub fn set_heartbeat_callback(&mut self, f: impl Fn(Duration) + Send + 'static) {
+        self.heartbeat_fn = Some(Box::new(f));
+    }
+
+    /// Number of messages `send_with_ack` buffers while waiting for a reply,
+    /// defaults to `DEFAULT_MAX_PENDING`. Once full, the oldest message is
+    /// dropped.
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending;
+
+        while self.pending.len() > max_pending {
+            self.pending.pop_front();
+        }
+    }
+
+    pub fn reconnect_stats(&self) -> ReconnectStats {
+        self.reconnect_stats.lock().unwrap().clone()
+    }
+
+    // `Ok(false)` while a background reconnect is still running
+    fn ensure_connected(&mut self, op: &str, block: Block) -> Result<bool> {
+        if self.connected() {
+            return Ok(true)
+        }
+
+        if self.reconnect.is_none() {
+            return Err(Error::Disconnected(op.to_string()))
+        }
+
+        if self.reconnecting.is_none() {
+            self.spawn_reconnect()?;
+        }
+
+        let rx = &self.reconnecting.as_ref().unwrap().rx;
+
+        let ret = match block {
+            Block::No => rx.try_recv().map_err(|err| err == mpsc::TryRecvError::Empty),
+            Block::Until(deadline) => {
+                rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    .map_err(|err| err == mpsc::RecvTimeoutError::Timeout)
+            }
+            Block::Forever => rx.recv().map_err(|_| false)
+        };
+
+        match ret {
+            Ok(ret) => {
+                self.reconnecting = None;
+                self.wire = Some(ret?);
+
+                Ok(true)
+            }
+            Err(true) => Ok(false),
+            Err(false) => {
+                self.reconnecting = None;
+
+                Err(Error::Disconnected(op.to_string()))
+            }
+        }
+    }
+
+    fn spawn_reconnect(&mut self) -> Result<()> {
+        let config = match &self.reconnect {
+            Some(config) => config.clone(),
+            None => return Ok(())
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let connector = self.connector.clone();
+        let stats = self.reconnect_stats.clone();
+        let cancel2 = cancel.clone();
+
+        thread::Builder::new().name("conn_reconnect".to_string()).spawn(move || {
+            let ret = retry(&connector, &stats, &config, &cancel2);
+            let _ = tx.send(ret);
+        })?;
+
+        self.reconnecting = Some(Reconnecting { rx, cancel });
+
+        Ok(())
+    }
+
+    // the wire is gone, start reconnecting right away
+    fn lost(&mut self) {
+        self.wire = None;
+
+        if self.reconnect.is_some() && self.reconnecting.is_none() {
+            if let Err(err) = self.spawn_reconnect() {
+                log::warn!("conn::reconnect, err: {}", err);
+            }
+        }
+    }
+
     pub fn disconnect(&mut self) {
+        self.reconnecting = None;
         self.wire = None
     }
 
@@ -54,16 +255,26 @@ impl Conn {
     }
 
     pub fn send(&mut self, message: Message) -> Result<()> {
-        if !self.connected() {
-            return Err(Error::Disconnected("conn::send".to_string()))
+        self.send_until(message, Block::No)
+    }
+
+    fn send_until(&mut self, message: Message, block: Block) -> Result<()> {
+        if !self.ensure_connected("conn::send", block)? {
+            return Err(Error::Disconnected("conn::send: reconnecting".to_string()))
         }
 
+        let ping = is_ping(&message);
+
         match self.wire.as_ref().unwrap().send(message) {
-            Ok(_) => (),
+            Ok(_) => {
+                if ping {
+                    self.last_ping_sent = Some(Instant::now());
+                }
+            }
             Err(err) => {
                 match err {
                     SendError::Disconnected(_) => {
-                        self.wire = None
+                        self.lost()
                     }
                     SendError::Full(_) => {
                         return Err(Error::Full("wire.send".to_string()))
@@ -76,17 +287,22 @@ impl Conn {
     }
 
     pub fn recv(&mut self) -> Result<Option<Message>> {
-        if !self.connected() {
-            return Err(Error::Disconnected("conn::recv".to_string()))
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(Some(message))
+        }
+
+        if !self.ensure_connected("conn::recv", Block::No)? {
+            return Ok(None)
         }
 
         match self.wire.as_ref().unwrap().recv() {
             Ok(message) => {
+                self.heartbeat(&message);
                 return Ok(Some(message))
             }
             Err(err) => {
                 if matches!(err, RecvError::Disconnected) {
-                    self.wire = None
+                    self.lost()
                 }
             }
         }
@@ -95,27 +311,288 @@ impl Conn {
     }
 
     pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Option<Message>> {
-        if !self.connected() {
-            return Err(Error::Disconnected("conn::wait".to_string()))
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(Some(message))
+        }
+
+        self.wait_wire(timeout.map(|timeout| Instant::now() + timeout))
+    }
+
+    /// Send `message` and block until a message carrying the same `_id`
+    /// arrives, or `timeout` elapses (`Ok(None)`). An `_id` is generated if
+    /// the message has none.
+    ///
+    /// Messages received in the meantime are buffered in order and returned
+    /// by subsequent `recv` / `wait` calls. At most `set_max_pending` of them
+    /// are kept, the oldest are dropped first.
+    pub fn send_with_ack(&mut self, mut message: Message, timeout: Duration) -> Result<Option<Message>> {
+        let id = match message.get_message_id(ID) {
+            Ok(id) => *id,
+            Err(_) => {
+                let id = MessageId::new();
+                message.insert(ID, id);
+                id
+            }
+        };
+
+        let deadline = Instant::now() + timeout;
+
+        self.send_until(message, Block::Until(deadline))?;
+
+        if let Some(pos) = self.pending.iter().position(|m| m.get_message_id(ID).ok() == Some(&id)) {
+            return Ok(self.pending.remove(pos))
+        }
+
+        loop {
+            if Instant::now() >= deadline {
+                return Ok(None)
+            }
+
+            match self.wait_wire(Some(deadline))? {
+                Some(reply) => {
+                    if reply.get_message_id(ID).ok() == Some(&id) {
+                        return Ok(Some(reply))
+                    }
+
+                    if self.pending.len() >= self.max_pending {
+                        log::warn!("conn::send_with_ack, pending full, dropping the oldest message");
+                        self.pending.pop_front();
+                    }
+
+                    if self.max_pending > 0 {
+                        self.pending.push_back(reply);
+                    }
+                }
+                None => {
+                    if !self.connected() {
+                        return Err(Error::Disconnected("conn::send_with_ack".to_string()))
+                    }
+                }
+            }
+        }
+    }
+
+    fn wait_wire(&mut self, deadline: Option<Instant>) -> Result<Option<Message>> {
+        let block = match deadline {
+            Some(deadline) => Block::Until(deadline),
+            None => Block::Forever
+        };
+
+        if !self.ensure_connected("conn::wait", block)? {
+            return Ok(None)
         }
 
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
         match self.wire.as_ref().unwrap().wait(timeout) {
             Ok(message) => {
+                self.heartbeat(&message);
                 return Ok(Some(message))
             }
             Err(err) => {
                 if matches!(err, RecvError::Disconnected) {
-                    self.wire = None
+                    self.lost()
                 }
             }
         }
 
         Ok(None)
     }
+
+    fn heartbeat(&mut self, message: &Message) {
+        if !is_ping(message) {
+            return
+        }
+
+        if let Some(sent) = self.last_ping_sent.take() {
+            if let Some(f) = &self.heartbeat_fn {
+                f(sent.elapsed());
+            }
+        }
+    }
+}
+
+// connects with exponential backoff until `config.max_attempts` is
+// exhausted or `cancel` is set
+fn retry(
+    connector: &Mutex<Box<dyn Connector>>,
+    stats: &Mutex<ReconnectStats>,
+    config: &ReconnectConfig,
+    cancel: &AtomicBool
+) -> Result<Wire<Message>> {
+    let mut attempt: u32 = 0;
+
+    loop {
+        log::debug!("conn::connect_with_retry, attempt: {}", attempt);
+
+        let ret = connector.lock().unwrap().connect();
+
+        match ret {
+            Ok(wire) => {
+                stats.lock().unwrap().attempts = attempt + 1;
+
+                return Ok(wire)
+            }
+            Err(err) => {
+                attempt = attempt.saturating_add(1);
+
+                {
+                    let mut stats = stats.lock().unwrap();
+                    stats.attempts = attempt;
+                    stats.last_error = Some(format!("{}", err));
+                }
+
+                if let Some(max_attempts) = config.max_attempts {
+                    if attempt >= max_attempts {
+                        return Err(err)
+                    }
+                }
+
+                thread::sleep(backoff(attempt - 1, config.base_delay, config.max_delay));
+
+                if cancel.load(Ordering::Relaxed) {
+                    return Err(err)
+                }
+            }
+        }
+    }
 }
 
-pub struct PortConnector {
-    pub port: Port<NsonCodec>,
+/// Wraps a `Conn` and writes a hex dump of the nson encoding of every message
+/// it sends (`>`) or receives (`<`) to `writer`. This is not what goes on the
+/// wire: the codec and crypto of the underlying transport are not applied.
+/// The messages themselves are left untouched.
+pub struct TracingConn {
+    inner: Conn,
+    writer: Box<dyn Write + Send>
+}
+
+impl TracingConn {
+    pub fn new(inner: Conn, writer: impl Write + Send + 'static) -> Self {
+        TracingConn {
+            inner,
+            writer: Box::new(writer)
+        }
+    }
+
+    pub fn inner(&self) -> &Conn {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut Conn {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> Conn {
+        self.inner
+    }
+
+    pub fn connected(&self) -> bool {
+        self.inner.connected()
+    }
+
+    pub fn connect(&mut self) -> Result<()> {
+        self.inner.connect()
+    }
+
+    pub fn disconnect(&mut self) {
+        self.inner.disconnect()
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<()> {
+        self.trace('>', &message);
+        self.inner.send(message)
+    }
+
+    pub fn recv(&mut self) -> Result<Option<Message>> {
+        let ret = self.inner.recv()?;
+
+        if let Some(message) = &ret {
+            self.trace('<', message);
+        }
+
+        Ok(ret)
+    }
+
+    /// See `Conn::send_with_ack`. Messages buffered while waiting are traced
+    /// when they are returned by `recv` / `wait`.
+    pub fn send_with_ack(&mut self, mut message: Message, timeout: Duration) -> Result<Option<Message>> {
+        if message.get_message_id(ID).is_err() {
+            message.insert(ID, MessageId::new());
+        }
+
+        self.trace('>', &message);
+
+        let ret = self.inner.send_with_ack(message, timeout)?;
+
+        if let Some(reply) = &ret {
+            self.trace('<', reply);
+        }
+
+        Ok(ret)
+    }
+
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Option<Message>> {
+        let ret = self.inner.wait(timeout)?;
+
+        if let Some(message) = &ret {
+            self.trace('<', message);
+        }
+
+        Ok(ret)
+    }
+
+    fn trace(&mut self, direction: char, message: &Message) {
+        let ret = message.to_bytes()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+            .and_then(|bytes| {
+                let chan = message.get_str(CHAN).unwrap_or("");
+                let id = message.get_message_id(ID).map(|id| id.to_hex()).unwrap_or_default();
+
+                writeln!(self.writer, "{} chan: {:?}, id: {:?}, nson len: {}", direction, chan, id, bytes.len())?;
+                hex_dump(&mut self.writer, direction, &bytes)
+            });
+
+        if let Err(err) = ret {
+            log::debug!("conn::trace, err: {}", err);
+        }
+    }
+}
+
+// offset | 16 hex bytes | ascii
+fn hex_dump(writer: &mut dyn Write, direction: char, bytes: &[u8]) -> io::Result<()> {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let mut hex = String::with_capacity(48);
+        let mut ascii = String::with_capacity(16);
+
+        for b in line {
+            hex.push_str(&format!("{:02x} ", b));
+            ascii.push(if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' });
+        }
+
+        writeln!(writer, "{} {:08x}  {:<48} |{}|", direction, i * 16, hex, ascii)?;
+    }
+
+    Ok(())
+}
+
+fn is_ping(message: &Message) -> bool {
+    message.get_str(CHAN).map(|chan| chan == PING).unwrap_or(false)
+}
+
+fn backoff(attempt: u32, base_delay: Duration, max_delay: Duration) -> Duration {
+    let delay = 1u32.checked_shl(attempt)
+        .and_then(|factor| base_delay.checked_mul(factor))
+        .unwrap_or(max_delay)
+        .min(max_delay);
+
+    let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+
+    delay + Duration::from_millis(jitter)
+}
+
+pub struct PortConnector<C: Codec = NsonCodec> {
+    pub port: Port<C>,
     pub addr: SocketAddr,
     pub slot_id: MessageId,
     pub root: bool,
@@ -123,10 +600,33 @@ pub struct PortConnector {
     pub crypto_options: Option<CryptoOptions>,
 }
 
-impl Connector for PortConnector {
+impl<C: Codec> Connector for PortConnector<C> {
     fn connect(&self) -> Result<Wire<Message>> {
         self.port.connect(
-            self.addr.clone(),
+            self.addr,
+            self.slot_id,
+            self.root,
+            self.attr.clone(),
+            self.crypto_options.clone(),
+            None
+        )
+    }
+}
+
+/// What `RoundRobinConnector` and `FailoverConnector` connect with, to
+/// whichever address they pick.
+pub struct PortConfig<C: Codec = NsonCodec> {
+    pub port: Port<C>,
+    pub slot_id: MessageId,
+    pub root: bool,
+    pub attr: Message,
+    pub crypto_options: Option<CryptoOptions>
+}
+
+impl<C: Codec> PortConfig<C> {
+    pub fn connect(&self, addr: SocketAddr) -> Result<Wire<Message>> {
+        self.port.connect(
+            addr,
             self.slot_id,
             self.root,
             self.attr.clone(),
@@ -136,6 +636,148 @@ impl Connector for PortConnector {
     }
 }
 
+/// Spreads connections over `addrs`, one address after another.
+pub struct RoundRobinConnector<C: Codec = NsonCodec> {
+    pub config: PortConfig<C>,
+    pub addrs: Vec<SocketAddr>,
+    next: AtomicUsize
+}
+
+impl<C: Codec> RoundRobinConnector<C> {
+    pub fn new(config: PortConfig<C>, addrs: Vec<SocketAddr>) -> Self {
+        RoundRobinConnector {
+            config,
+            addrs,
+            next: AtomicUsize::new(0)
+        }
+    }
+}
+
+impl<C: Codec> Connector for RoundRobinConnector<C> {
+    fn connect(&self) -> Result<Wire<Message>> {
+        if self.addrs.is_empty() {
+            return Err(Error::Disconnected("conn::round_robin: no address".to_string()))
+        }
+
+        self.config.connect(self.addrs[next_index(&self.next, self.addrs.len())])
+    }
+}
+
+fn next_index(next: &AtomicUsize, len: usize) -> usize {
+    next.fetch_add(1, Ordering::Relaxed) % len
+}
+
+/// Connects to the first reachable address of `addrs`, in order.
+///
+/// Only an unreachable address (connection refused, timed out or address
+/// not available) is failed over, and then skipped until its backoff,
+/// growing from `base_delay` up to `max_delay`, has elapsed. Any other
+/// error, such as a rejected handshake, is returned as is.
+pub struct FailoverConnector<C: Codec = NsonCodec> {
+    pub config: PortConfig<C>,
+    pub addrs: Vec<SocketAddr>,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    unavailable: Mutex<Unavailable>
+}
+
+impl<C: Codec> FailoverConnector<C> {
+    pub fn new(config: PortConfig<C>, addrs: Vec<SocketAddr>) -> Self {
+        let reconnect = ReconnectConfig::default();
+
+        FailoverConnector {
+            config,
+            addrs,
+            base_delay: reconnect.base_delay,
+            max_delay: reconnect.max_delay,
+            unavailable: Mutex::new(Unavailable::default())
+        }
+    }
+}
+
+impl<C: Codec> Connector for FailoverConnector<C> {
+    fn connect(&self) -> Result<Wire<Message>> {
+        failover(&self.addrs, &self.unavailable, self.base_delay, self.max_delay, |addr| {
+            self.config.connect(addr)
+        })
+    }
+}
+
+fn failover<T>(
+    addrs: &[SocketAddr],
+    unavailable: &Mutex<Unavailable>,
+    base_delay: Duration,
+    max_delay: Duration,
+    mut connect: impl FnMut(SocketAddr) -> Result<T>
+) -> Result<T> {
+    let mut last_err = None;
+
+    for addr in addrs {
+        // the lock is never held across the blocking connect
+        if !unavailable.lock().unwrap().is_available(addr, Instant::now()) {
+            continue
+        }
+
+        match connect(*addr) {
+            Ok(ret) => {
+                unavailable.lock().unwrap().succeeded(addr);
+
+                return Ok(ret)
+            }
+            Err(err) => {
+                log::debug!("conn::failover, addr: {}, err: {}", addr, err);
+
+                if !is_unreachable(&err) {
+                    return Err(err)
+                }
+
+                unavailable.lock().unwrap().failed(*addr, Instant::now(), base_delay, max_delay);
+
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| Error::Disconnected("conn::failover: no address available".to_string())))
+}
+
+fn is_unreachable(err: &Error) -> bool {
+    match err {
+        Error::ConnectionRefused(_) => true,
+        Error::IoError(err) => matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut | io::ErrorKind::AddrNotAvailable
+        ),
+        _ => false
+    }
+}
+
+// addresses skipped by `FailoverConnector` after failing to connect
+#[derive(Default)]
+struct Unavailable {
+    // address => (consecutive failures, skipped until)
+    addrs: HashMap<SocketAddr, (u32, Instant)>
+}
+
+impl Unavailable {
+    fn is_available(&self, addr: &SocketAddr, now: Instant) -> bool {
+        match self.addrs.get(addr) {
+            Some((_, until)) => *until <= now,
+            None => true
+        }
+    }
+
+    fn failed(&mut self, addr: SocketAddr, now: Instant, base_delay: Duration, max_delay: Duration) {
+        let failures = self.addrs.get(&addr).map(|(failures, _)| *failures).unwrap_or(0);
+        let until = now + backoff(failures, base_delay, max_delay);
+        self.addrs.insert(addr, (failures.saturating_add(1), until));
+    }
+
+    fn succeeded(&mut self, addr: &SocketAddr) {
+        self.addrs.remove(addr);
+    }
+}
+
 pub struct SocketConnector {
     pub socket: Socket,
     pub slot_id: MessageId,
@@ -154,3 +796,533 @@ impl Connector for SocketConnector {
         )
     }
 }
+
+/// Accepts one connection on a local port, answers the queen handshake and
+/// echoes every message back.
+#[cfg(test)]
+pub(crate) fn hand_server() -> SocketAddr {
+    use std::net::TcpListener;
+
+    use queen::dict::{HAND, CODE};
+    use queen::util::message::read_block;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut codec = NsonCodec::new();
+
+        let bytes = read_block(&mut stream, Some(1024)).unwrap();
+        let mut message = codec.decode(&None, bytes).unwrap();
+        assert_eq!(message.get_str(CHAN).unwrap(), HAND);
+
+        message.insert(CODE, 0i32);
+        stream.write_all(&codec.encode(&None, message).unwrap()).unwrap();
+
+        while let Ok(bytes) = read_block(&mut stream, None) {
+            if stream.write_all(&bytes).is_err() {
+                return
+            }
+        }
+    });
+
+    addr
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::time::{Duration, Instant};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use queen::Wire;
+    use queen::error::{Result, Error};
+    use queen::nson::{Message, MessageId, msg};
+    use queen::dict::{ID, CHAN, PING, CODE};
+
+    use super::{
+        Conn, Connector, TracingConn, ReconnectConfig, Unavailable,
+        DEFAULT_RECONNECT_ATTEMPTS, backoff, hex_dump, is_ping, next_index, failover
+    };
+
+    struct RefusedConnector;
+
+    impl Connector for RefusedConnector {
+        fn connect(&self) -> Result<Wire<Message>> {
+            Err(Error::Disconnected("refused".to_string()))
+        }
+    }
+
+    // hands out one end of an in-memory pipe, once
+    struct PipeConnector(Mutex<Option<Wire<Message>>>);
+
+    impl Connector for PipeConnector {
+        fn connect(&self) -> Result<Wire<Message>> {
+            self.0.lock().unwrap().take().ok_or_else(|| Error::Disconnected("pipe".to_string()))
+        }
+    }
+
+    fn pipe() -> (Conn, Wire<Message>) {
+        let (wire1, wire2) = Wire::pipe(64, Message::new()).unwrap();
+
+        let mut conn = Conn::new(PipeConnector(Mutex::new(Some(wire1))));
+        conn.connect().unwrap();
+
+        (conn, wire2)
+    }
+
+    #[test]
+    fn connect_with_retry_gives_up() {
+        let mut conn = Conn::new(RefusedConnector);
+
+        let ret = conn.connect_with_retry(Some(3), Duration::from_millis(1), Duration::from_millis(2));
+        assert!(ret.is_err());
+        assert!(!conn.connected());
+
+        let stats = conn.reconnect_stats();
+        assert_eq!(stats.attempts, 3);
+        assert!(stats.last_error.is_some());
+    }
+
+    #[test]
+    fn auto_reconnect_is_bounded() {
+        let mut conn = Conn::new(RefusedConnector);
+        conn.auto_reconnect(ReconnectConfig {
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+            ..ReconnectConfig::default()
+        });
+
+        assert!(conn.wait(None).is_err());
+        assert_eq!(conn.reconnect_stats().attempts, DEFAULT_RECONNECT_ATTEMPTS);
+    }
+
+    // refuses `fails` times, then hands out the wire
+    struct FlakyConnector {
+        fails: AtomicUsize,
+        wire: Mutex<Option<Wire<Message>>>
+    }
+
+    impl Connector for FlakyConnector {
+        fn connect(&self) -> Result<Wire<Message>> {
+            if self.fails.load(Ordering::SeqCst) > 0 {
+                self.fails.fetch_sub(1, Ordering::SeqCst);
+                return Err(Error::ConnectionRefused("flaky".to_string()))
+            }
+
+            self.wire.lock().unwrap().take().ok_or_else(|| Error::Disconnected("flaky".to_string()))
+        }
+    }
+
+    #[test]
+    fn auto_reconnect_in_background() {
+        let (wire1, wire2) = Wire::pipe(64, Message::new()).unwrap();
+
+        let mut conn = Conn::new(FlakyConnector {
+            fails: AtomicUsize::new(3),
+            wire: Mutex::new(Some(wire1))
+        });
+        conn.auto_reconnect(ReconnectConfig {
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            ..ReconnectConfig::default()
+        });
+
+        // starts the reconnect without blocking on it
+        assert!(conn.recv().unwrap().is_none());
+        assert!(!conn.connected());
+        assert!(conn.send(msg!{"n": 1}).is_err());
+
+        wire2.send(msg!{"n": 2}).unwrap();
+
+        let message = conn.wait(Some(Duration::from_secs(5))).unwrap().unwrap();
+        assert_eq!(message.get_i32("n").unwrap(), 2);
+        assert!(conn.connected());
+        assert_eq!(conn.reconnect_stats().attempts, 4);
+    }
+
+    #[test]
+    fn auto_reconnect_after_disconnect() {
+        let (wire1, wire2) = Wire::pipe(64, Message::new()).unwrap();
+
+        let connector = FlakyConnector {
+            fails: AtomicUsize::new(0),
+            wire: Mutex::new(Some(wire1))
+        };
+
+        let mut conn = Conn::new(connector);
+        conn.connect().unwrap();
+        conn.auto_reconnect(ReconnectConfig {
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+            ..ReconnectConfig::default()
+        });
+
+        drop(wire2);
+        assert!(conn.wait(Some(Duration::from_millis(100))).unwrap().is_none());
+        assert!(!conn.connected());
+
+        // the connector has no wire left, so the reconnect gives up
+        assert!(conn.wait(None).is_err());
+        assert_eq!(conn.reconnect_stats().attempts, DEFAULT_RECONNECT_ATTEMPTS);
+    }
+
+    #[test]
+    fn auto_reconnect_honors_wait_timeout() {
+        let mut conn = Conn::new(RefusedConnector);
+        conn.auto_reconnect(ReconnectConfig {
+            max_attempts: None,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20)
+        });
+
+        let start = Instant::now();
+        assert!(conn.wait(Some(Duration::from_millis(50))).unwrap().is_none());
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let start = Instant::now();
+        assert!(conn.send_with_ack(Message::new(), Duration::from_millis(50)).is_err());
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn send_with_ack_buffers_unrelated_messages() {
+        let (mut conn, peer) = pipe();
+
+        let id = MessageId::new();
+
+        peer.send(msg!{"n": 1}).unwrap();
+        peer.send(msg!{"n": 2}).unwrap();
+        peer.send(msg!{ID: id, "n": 3}).unwrap();
+
+        let reply = conn.send_with_ack(msg!{ID: id}, Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(reply.get_i32("n").unwrap(), 3);
+
+        let sent = peer.recv().unwrap();
+        assert_eq!(sent.get_message_id(ID).unwrap(), &id);
+
+        assert_eq!(conn.recv().unwrap().unwrap().get_i32("n").unwrap(), 1);
+        assert_eq!(conn.wait(Some(Duration::from_millis(10))).unwrap().unwrap().get_i32("n").unwrap(), 2);
+        assert!(conn.wait(Some(Duration::from_millis(10))).unwrap().is_none());
+    }
+
+    #[test]
+    fn send_with_ack_matches_buffered_reply() {
+        let (mut conn, peer) = pipe();
+
+        let id1 = MessageId::new();
+        let id2 = MessageId::new();
+
+        peer.send(msg!{ID: id2, "n": 2}).unwrap();
+        peer.send(msg!{ID: id1, "n": 1}).unwrap();
+
+        let reply = conn.send_with_ack(msg!{ID: id1}, Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(reply.get_i32("n").unwrap(), 1);
+
+        let reply = conn.send_with_ack(msg!{ID: id2}, Duration::from_millis(10)).unwrap().unwrap();
+        assert_eq!(reply.get_i32("n").unwrap(), 2);
+
+        assert_eq!(peer.recv().unwrap().get_message_id(ID).unwrap(), &id1);
+        assert_eq!(peer.recv().unwrap().get_message_id(ID).unwrap(), &id2);
+        assert!(conn.recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn send_with_ack_caps_pending() {
+        let (mut conn, peer) = pipe();
+        conn.set_max_pending(2);
+
+        let id = MessageId::new();
+
+        for n in 1..=3 {
+            peer.send(msg!{"n": n}).unwrap();
+        }
+        peer.send(msg!{ID: id}).unwrap();
+
+        assert!(conn.send_with_ack(msg!{ID: id}, Duration::from_secs(1)).unwrap().is_some());
+
+        assert_eq!(conn.recv().unwrap().unwrap().get_i32("n").unwrap(), 2);
+        assert_eq!(conn.recv().unwrap().unwrap().get_i32("n").unwrap(), 3);
+        assert!(conn.recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn send_with_ack_from_two_threads() {
+        let (conn, peer) = pipe();
+        let conn = Arc::new(Mutex::new(conn));
+
+        let ids = [MessageId::new(), MessageId::new()];
+
+        // the reply to the second request is already on the wire, so
+        // whichever caller runs first may have to buffer the other's reply
+        peer.send(msg!{ID: ids[1], "n": 1}).unwrap();
+
+        let server = thread::spawn(move || {
+            while let Ok(request) = peer.wait(Some(Duration::from_secs(1))) {
+                let id = *request.get_message_id(ID).unwrap();
+
+                if id != ids[1] {
+                    peer.send(msg!{ID: id, "n": 0}).unwrap();
+                }
+            }
+        });
+
+        let callers: Vec<_> = ids.iter().enumerate().map(|(n, id)| {
+            let conn = conn.clone();
+            let id = *id;
+
+            thread::spawn(move || {
+                let reply = conn.lock().unwrap()
+                    .send_with_ack(msg!{ID: id}, Duration::from_secs(1))
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(reply.get_message_id(ID).unwrap(), &id);
+                assert_eq!(reply.get_i32("n").unwrap(), n as i32);
+            })
+        }).collect();
+
+        for caller in callers {
+            caller.join().unwrap();
+        }
+
+        assert!(conn.lock().unwrap().recv().unwrap().is_none());
+
+        drop(conn);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn send_with_ack_times_out() {
+        let (mut conn, peer) = pipe();
+
+        peer.send(msg!{"n": 1}).unwrap();
+
+        let ret = conn.send_with_ack(Message::new(), Duration::from_millis(20)).unwrap();
+        assert!(ret.is_none());
+
+        let sent = peer.recv().unwrap();
+        assert!(sent.get_message_id(ID).is_ok());
+
+        assert_eq!(conn.recv().unwrap().unwrap().get_i32("n").unwrap(), 1);
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn tracing_conn_traces_send_and_recv() {
+        let (conn, peer) = pipe();
+
+        let out = SharedBuf::default();
+        let mut conn = TracingConn::new(conn, out.clone());
+
+        let sent = msg!{CHAN: "hello", "n": 1};
+        conn.send(sent.clone()).unwrap();
+        assert_eq!(peer.recv().unwrap(), sent);
+
+        let id = MessageId::new();
+        peer.send(msg!{"n": 2}).unwrap();
+        peer.send(msg!{ID: id}).unwrap();
+
+        let reply = conn.send_with_ack(msg!{ID: id}, Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(reply.get_message_id(ID).unwrap(), &id);
+
+        assert_eq!(conn.recv().unwrap().unwrap().get_i32("n").unwrap(), 2);
+
+        let out = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let headers: Vec<&str> = out.lines().filter(|line| line.contains("nson len")).collect();
+
+        let len = sent.to_bytes().unwrap().len();
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers[0], format!("> chan: \"hello\", id: \"\", nson len: {}", len));
+        assert!(headers[1].starts_with(&format!("> chan: \"\", id: {:?}", id.to_hex())));
+        assert!(headers[2].starts_with(&format!("< chan: \"\", id: {:?}", id.to_hex())));
+        assert!(headers[3].starts_with("< chan: \"\", id: \"\""));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let base = Duration::from_millis(10);
+        let max = Duration::from_millis(100);
+
+        assert!(backoff(0, base, max) >= base);
+        assert!(backoff(3, base, max) >= Duration::from_millis(80));
+        assert!(backoff(40, base, max) <= max + max / 2);
+    }
+
+    #[test]
+    fn round_robin_spreads_evenly() {
+        let next = AtomicUsize::new(0);
+
+        let mut counts = [0; 2];
+        for _ in 0..100 {
+            counts[next_index(&next, 2)] += 1;
+        }
+
+        assert_eq!(counts, [50, 50]);
+
+        let next = AtomicUsize::new(usize::MAX);
+        assert_eq!(next_index(&next, 3), usize::MAX % 3);
+        assert_eq!(next_index(&next, 3), 0);
+    }
+
+    fn refused() -> Error {
+        Error::IoError(io::Error::from(io::ErrorKind::ConnectionRefused))
+    }
+
+    #[test]
+    fn failover_skips_unreachable() {
+        let a: SocketAddr = "127.0.0.1:8888".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:8889".parse().unwrap();
+        let unavailable = Mutex::new(Unavailable::default());
+        let delay = Duration::from_secs(10);
+
+        let mut tried = Vec::new();
+        let ret = failover(&[a, b], &unavailable, delay, delay, |addr| {
+            tried.push(addr);
+            if addr == a { Err(refused()) } else { Ok(addr) }
+        });
+
+        assert_eq!(ret.unwrap(), b);
+        assert_eq!(tried, [a, b]);
+
+        // a is backing off
+        let mut tried = Vec::new();
+        let ret = failover(&[a, b], &unavailable, delay, delay, |addr| {
+            tried.push(addr);
+            Ok(addr)
+        });
+
+        assert_eq!(ret.unwrap(), b);
+        assert_eq!(tried, [b]);
+
+        let ret = failover(&[a, b], &unavailable, delay, delay, |_| Err::<(), _>(refused()));
+        assert!(matches!(ret, Err(Error::IoError(_))));
+
+        let ret = failover(&[a, b], &unavailable, delay, delay, |_| Ok(()));
+        assert!(matches!(ret, Err(Error::Disconnected(_))));
+    }
+
+    #[test]
+    fn failover_returns_other_errors() {
+        let a: SocketAddr = "127.0.0.1:8888".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:8889".parse().unwrap();
+        let unavailable = Mutex::new(Unavailable::default());
+        let delay = Duration::from_secs(10);
+
+        // a rejected handshake is not an unreachable address
+        let mut tried = Vec::new();
+        let ret = failover(&[a, b], &unavailable, delay, delay, |addr| {
+            tried.push(addr);
+            Err::<(), _>(Error::InvalidData("handshake".to_string()))
+        });
+
+        assert!(matches!(ret, Err(Error::InvalidData(_))));
+        assert_eq!(tried, [a]);
+        assert!(unavailable.lock().unwrap().is_available(&a, Instant::now()));
+    }
+
+    #[test]
+    fn unavailable_backs_off() {
+        let addr = "127.0.0.1:8888".parse().unwrap();
+        let other = "127.0.0.1:8889".parse().unwrap();
+        let base = Duration::from_millis(100);
+        let max = Duration::from_secs(1);
+
+        let mut unavailable = Unavailable::default();
+        let now = Instant::now();
+        assert!(unavailable.is_available(&addr, now));
+
+        unavailable.failed(addr, now, base, max);
+        assert!(!unavailable.is_available(&addr, now));
+        assert!(!unavailable.is_available(&addr, now + base - Duration::from_millis(1)));
+        assert!(unavailable.is_available(&addr, now + base + base / 2));
+        assert!(unavailable.is_available(&other, now));
+
+        // the second consecutive failure doubles the delay
+        unavailable.failed(addr, now, base, max);
+        assert!(!unavailable.is_available(&addr, now + base * 2 - Duration::from_millis(1)));
+        assert!(unavailable.is_available(&addr, now + base * 3));
+
+        for _ in 0..40 {
+            unavailable.failed(addr, now, base, max);
+        }
+        assert!(!unavailable.is_available(&addr, now + max - Duration::from_millis(1)));
+        assert!(unavailable.is_available(&addr, now + max + max / 2));
+
+        unavailable.succeeded(&addr);
+        assert!(unavailable.is_available(&addr, now));
+    }
+
+    #[test]
+    fn heartbeat_callback_reports_rtt() {
+        let (mut conn, peer) = pipe();
+
+        let rtts = Arc::new(Mutex::new(Vec::new()));
+        let rtts2 = rtts.clone();
+        conn.set_heartbeat_callback(move |rtt| rtts2.lock().unwrap().push(rtt));
+
+        let start = Instant::now();
+        conn.send(msg!{CHAN: PING}).unwrap();
+
+        // the peer echoes the PING back with CODE Ok, as the server does
+        let mut ping = peer.recv().unwrap();
+        ping.insert(CODE, 0);
+        peer.send(ping.clone()).unwrap();
+
+        let reply = conn.wait(Some(Duration::from_secs(1))).unwrap().unwrap();
+        assert_eq!(reply.get_i32(CODE).unwrap(), 0);
+
+        {
+            let rtts = rtts.lock().unwrap();
+            assert_eq!(rtts.len(), 1);
+            assert!(rtts[0] <= start.elapsed());
+            assert!(rtts[0] < Duration::from_millis(500));
+        }
+
+        // a PING without an outstanding request does not fire the callback
+        peer.send(ping).unwrap();
+        assert!(conn.recv().unwrap().is_some());
+        assert_eq!(rtts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn ping_is_detected_by_chan() {
+        let mut message = Message::new();
+        assert!(!is_ping(&message));
+
+        message.insert(CHAN, "hello");
+        assert!(!is_ping(&message));
+
+        message.insert(CHAN, PING);
+        assert!(is_ping(&message));
+    }
+
+    #[test]
+    fn hex_dump_format() {
+        let mut out = Vec::new();
+        hex_dump(&mut out, '>', b"hello, world\x00\x01\x02\xffabc").unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "> 00000000  68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 00 01 02 ff  |hello, world....|");
+        assert_eq!(lines[1], format!("> 00000010  {:<48} |abc|", "61 62 63 "));
+    }
+}
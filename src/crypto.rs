@@ -0,0 +1,146 @@
+use std::convert::TryInto;
+
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::aead::consts::U12;
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::Rng;
+
+use crate::packet::{Crypto, PacketError};
+
+pub const NONCE_SIZE: usize = 12;
+/// Authentication tag appended by every supported AEAD
+pub const TAG_SIZE: usize = 16;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key128(pub [u8; 16]);
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key256(pub [u8; 32]);
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum Key {
+    Aes128Gcm(Key128),
+    Aes256Gcm(Key256),
+    ChaCha20Poly1305(Key256)
+}
+
+impl Key {
+    pub fn crypto(&self) -> Crypto {
+        match self {
+            Key::Aes128Gcm(_) => Crypto::Aes128Gcm,
+            Key::Aes256Gcm(_) => Crypto::Aes256Gcm,
+            Key::ChaCha20Poly1305(_) => Crypto::ChaCha20Poly1305
+        }
+    }
+}
+
+/// Keys used to encrypt and decrypt packet bodies.
+///
+/// Several keys can be held at once to allow key rotation: the sender
+/// encrypts with the active key and stamps its id into the packet header,
+/// the receiver looks the key up by that id and the header's `Crypto`.
+/// Key ids must fit in 3 bits, larger ids are rejected with
+/// `PacketError::InvalidKeyId`.
+#[derive(Clone)]
+pub struct PacketCrypto {
+    keys: Vec<(u8, Key)>,
+    active: u8
+}
+
+impl PacketCrypto {
+    pub const MAX_KEY_ID: u8 = 0b111;
+
+    pub fn new(key_id: u8, key: Key) -> Result<Self, PacketError> {
+        let mut crypto = PacketCrypto {
+            keys: Vec::new(),
+            active: key_id
+        };
+
+        crypto.add_key(key_id, key)?;
+
+        Ok(crypto)
+    }
+
+    /// Adds a key, replacing any key with the same id and algorithm
+    pub fn add_key(&mut self, key_id: u8, key: Key) -> Result<(), PacketError> {
+        if key_id > Self::MAX_KEY_ID {
+            return Err(PacketError::InvalidKeyId(key_id))
+        }
+
+        let crypto = key.crypto();
+        self.keys.retain(|(id, k)| *id != key_id || k.crypto() != crypto);
+        self.keys.push((key_id, key));
+
+        Ok(())
+    }
+
+    /// Removes the keys with this id for every algorithm
+    pub fn remove_key(&mut self, key_id: u8) {
+        self.keys.retain(|(id, _)| *id != key_id);
+    }
+
+    /// Returns false if no key with this id is known
+    pub fn set_active(&mut self, key_id: u8) -> bool {
+        if !self.keys.iter().any(|(id, _)| *id == key_id) {
+            return false
+        }
+
+        self.active = key_id;
+
+        true
+    }
+
+    pub fn active(&self) -> u8 {
+        self.active
+    }
+
+    pub fn key(&self, crypto: Crypto, key_id: u8) -> Option<&Key> {
+        self.keys.iter()
+            .find(|(id, key)| *id == key_id && key.crypto() == crypto)
+            .map(|(_, key)| key)
+    }
+}
+
+/// Returns `nonce || ciphertext`
+pub(crate) fn seal(key: &Key, aad: &[u8], body: &[u8]) -> Result<Vec<u8>, PacketError> {
+    match key {
+        Key::Aes128Gcm(key) => seal_with(Aes128Gcm::new((&key.0).into()), aad, body),
+        Key::Aes256Gcm(key) => seal_with(Aes256Gcm::new((&key.0).into()), aad, body),
+        Key::ChaCha20Poly1305(key) => seal_with(ChaCha20Poly1305::new((&key.0).into()), aad, body)
+    }
+}
+
+pub(crate) fn open(key: &Key, aad: &[u8], body: &[u8]) -> Result<Vec<u8>, PacketError> {
+    match key {
+        Key::Aes128Gcm(key) => open_with(Aes128Gcm::new((&key.0).into()), aad, body),
+        Key::Aes256Gcm(key) => open_with(Aes256Gcm::new((&key.0).into()), aad, body),
+        Key::ChaCha20Poly1305(key) => open_with(ChaCha20Poly1305::new((&key.0).into()), aad, body)
+    }
+}
+
+fn seal_with<A: Aead<NonceSize = U12>>(cipher: A, aad: &[u8], body: &[u8]) -> Result<Vec<u8>, PacketError> {
+    // random rather than a counter, so restarts never reuse a nonce
+    let nonce: [u8; NONCE_SIZE] = rand::thread_rng().gen();
+
+    let ciphertext = cipher.encrypt((&nonce).into(), Payload { msg: body, aad })
+        .map_err(|_| PacketError::EncryptionFailed)?;
+
+    let mut buf = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    buf.extend_from_slice(&nonce);
+    buf.extend(ciphertext);
+
+    Ok(buf)
+}
+
+fn open_with<A: Aead<NonceSize = U12>>(cipher: A, aad: &[u8], body: &[u8]) -> Result<Vec<u8>, PacketError> {
+    if body.len() < NONCE_SIZE {
+        return Err(PacketError::Truncated)
+    }
+
+    let (nonce, ciphertext) = body.split_at(NONCE_SIZE);
+    let nonce: [u8; NONCE_SIZE] = nonce.try_into().unwrap();
+
+    cipher.decrypt((&nonce).into(), Payload { msg: ciphertext, aad })
+        .map_err(|_| PacketError::DecryptionFailed)
+}
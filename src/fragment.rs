@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::packet::{self, Packet, Compress, Crypto, MTU, HEADER_SIZE};
+use crate::crypto::{NONCE_SIZE, TAG_SIZE};
+
+/// Splits packets whose encoded size exceeds `MTU`. Packets that already
+/// fit are returned unchanged.
+///
+/// A compressed body is compressed once, before splitting, and fragments are
+/// sent uncompressed.
+///
+/// Fragments have `Header::FRAGMENT` set and carry their index in
+/// `Header::code`, so a fragmented packet can have at most 256 fragments.
+/// Every fragment but the last has `Header::MORE_FRAGMENTS` set, and the
+/// last one ends with the original `Compress` and `code` bytes.
+pub struct Fragmenter;
+
+impl Fragmenter {
+    pub fn fragment(packet: &Packet) -> io::Result<Vec<Packet>> {
+        let mut overhead = HEADER_SIZE + packet.chan.len() + 1;
+        if packet.header.crypto()? != Crypto::None {
+            overhead += NONCE_SIZE + TAG_SIZE;
+        }
+
+        let compress = packet.header.compress()?;
+        let body = packet::compress(&packet.header, &packet.body)?;
+
+        if overhead + body.len() <= MTU as usize {
+            return Ok(vec![packet.clone()])
+        }
+
+        // two bytes are left for the original compress and code
+        if overhead + 2 >= MTU as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "chan too long to fragment"))
+        }
+
+        let chunk_size = MTU as usize - overhead - 2;
+
+        let chunks: Vec<&[u8]> = body.chunks(chunk_size).collect();
+
+        if chunks.len() > u8::MAX as usize + 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "body too large to fragment"))
+        }
+
+        let last = chunks.len() - 1;
+
+        let fragments = chunks.into_iter().enumerate().map(|(index, chunk)| {
+            let mut header = packet.header.clone();
+            header.set_fragment(true);
+            header.set_compress(Compress::None);
+            header.set_code(index as u8);
+            header.set_more_fragments(index != last);
+
+            let mut body = chunk.to_vec();
+            if index == last {
+                body.push(compress as u8);
+                body.push(packet.header.code());
+            }
+
+            Packet {
+                header,
+                chan: packet.chan.clone(),
+                body
+            }
+        }).collect();
+
+        Ok(fragments)
+    }
+}
+
+pub struct Reassembler {
+    timeout: Duration,
+    pending: HashMap<(u16, String), Pending>
+}
+
+struct Pending {
+    fragments: Vec<Option<Packet>>,
+    total: Option<usize>,
+    started_at: Instant
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Reassembler {
+            timeout,
+            pending: HashMap::new()
+        }
+    }
+
+    /// Returns the reassembled packet once its last missing fragment arrives,
+    /// with the header and code it had before fragmenting. Packets that are
+    /// not fragments are returned as is.
+    pub fn push(&mut self, packet: Packet) -> Option<Packet> {
+        if !packet.header.fragment() {
+            return Some(packet)
+        }
+
+        let key = (packet.header.message_id(), packet.chan.clone());
+        let index = packet.header.code() as usize;
+        let more = packet.header.more_fragments();
+
+        let pending = self.pending.entry(key.clone()).or_insert_with(|| Pending {
+            fragments: Vec::new(),
+            total: None,
+            started_at: Instant::now()
+        });
+
+        if !more {
+            pending.total = Some(index + 1);
+        }
+
+        if let Some(total) = pending.total {
+            if index >= total {
+                return None
+            }
+        }
+
+        if pending.fragments.len() <= index {
+            pending.fragments.resize_with(index + 1, || None);
+        }
+
+        pending.fragments[index] = Some(packet);
+
+        let total = pending.total?;
+
+        if pending.fragments.len() != total || pending.fragments.iter().any(Option::is_none) {
+            return None
+        }
+
+        let pending = self.pending.remove(&key)?;
+
+        let mut fragments = pending.fragments.into_iter().flatten();
+        let mut packet = fragments.next()?;
+
+        for fragment in fragments {
+            packet.body.extend(fragment.body);
+        }
+
+        let code = packet.body.pop()?;
+        let compress = Compress::try_from(packet.body.pop()?).ok()?;
+
+        packet.header.set_code(code);
+        packet.header.set_compress(compress);
+        packet.header.set_fragment(false);
+        packet.header.set_more_fragments(false);
+
+        match packet::decompress(&packet.header, &packet.body) {
+            Ok(body) => packet.body = body,
+            Err(err) => {
+                log::debug!("reassembler::push, message_id: {}, err: {}", packet.header.message_id(), err);
+                return None
+            }
+        }
+
+        Some(packet)
+    }
+
+    /// Drops incomplete sequences older than the timeout and returns their
+    /// message ids.
+    pub fn expired(&mut self) -> Vec<u16> {
+        let timeout = self.timeout;
+        let mut expired = Vec::new();
+
+        self.pending.retain(|(message_id, _), pending| {
+            if pending.started_at.elapsed() >= timeout {
+                expired.push(*message_id);
+                return false
+            }
+
+            true
+        });
+
+        expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::packet::{Packet, Type, Crypto, MTU, test_packet};
+    use crate::crypto::{PacketCrypto, Key, Key128};
+
+    use super::{Fragmenter, Reassembler};
+
+    fn body(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn fragment_and_reassemble() {
+        let mut packet = test_packet(42, Type::Con, "test", &body(10 * 1024));
+        packet.header.set_code(9);
+
+        let fragments = Fragmenter::fragment(&packet).unwrap();
+        assert_eq!(fragments.len(), 8);
+
+        for (i, fragment) in fragments.iter().enumerate() {
+            assert!(fragment.header.fragment());
+            assert_eq!(fragment.header.code() as usize, i);
+            assert_eq!(fragment.header.more_fragments(), i != 7);
+            assert!(fragment.to_bytes(None).unwrap().len() <= MTU as usize);
+        }
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+
+        let mut result = None;
+        for fragment in fragments.into_iter().rev() {
+            assert!(result.is_none());
+            result = reassembler.push(fragment);
+        }
+
+        assert_eq!(result.unwrap(), packet);
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn fragment_and_reassemble_encrypted() {
+        let crypto = PacketCrypto::new(0, Key::Aes128Gcm(Key128([3; 16]))).unwrap();
+
+        let mut packet = test_packet(42, Type::Con, "test", &body(3000));
+        packet.header.set_crypto(Crypto::Aes128Gcm);
+
+        let fragments = Fragmenter::fragment(&packet).unwrap();
+        assert_eq!(fragments.len(), 3);
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+
+        let mut result = None;
+        for fragment in fragments {
+            let bytes = fragment.to_bytes(Some(&crypto)).unwrap();
+            assert!(bytes.len() <= MTU as usize);
+
+            result = reassembler.push(Packet::from_bytes(&bytes, false, Some(&crypto)).unwrap());
+        }
+
+        assert_eq!(result.unwrap(), packet);
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    #[test]
+    fn fragment_and_reassemble_compressed() {
+        use crate::packet::Compress;
+
+        let mut compress = Vec::new();
+        #[cfg(feature = "gzip")]
+        compress.push(Compress::Gzip);
+        #[cfg(feature = "zstd")]
+        compress.push(Compress::Zstd);
+
+        for compress in compress {
+            // random bytes grow when compressed
+            let mut packet = test_packet(42, Type::Con, "test", &[]);
+            packet.header.set_compress(compress);
+            packet.body = (0..5000).map(|_| rand::random()).collect();
+
+            let fragments = Fragmenter::fragment(&packet).unwrap();
+            assert_eq!(fragments.len(), 4);
+
+            let mut reassembler = Reassembler::new(Duration::from_secs(5));
+
+            let mut result = None;
+            for fragment in fragments {
+                assert_eq!(fragment.header.compress().unwrap(), Compress::None);
+
+                let bytes = fragment.to_bytes(None).unwrap();
+                assert!(bytes.len() <= MTU as usize);
+
+                result = reassembler.push(Packet::from_bytes(&bytes, false, None).unwrap());
+            }
+
+            assert_eq!(result.unwrap(), packet);
+
+            // a body that compresses below MTU is not fragmented
+            packet.body = vec![0; 10 * 1024];
+            assert_eq!(Fragmenter::fragment(&packet).unwrap(), vec![packet.clone()]);
+        }
+    }
+
+    #[test]
+    fn single_fragment() {
+        let mut packet = test_packet(42, Type::Con, "test", &body(100));
+        packet.header.set_code(5);
+
+        let fragments = Fragmenter::fragment(&packet).unwrap();
+        assert_eq!(fragments, vec![packet.clone()]);
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        assert_eq!(reassembler.push(fragments[0].clone()).unwrap(), packet);
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn unfragmented_coded_packet() {
+        let mut packet = test_packet(42, Type::Con, "test", &body(1));
+        packet.header.set_code(5);
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        assert_eq!(reassembler.push(packet.clone()).unwrap(), packet);
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn expired() {
+        let packet = test_packet(42, Type::Con, "test", &body(4000));
+
+        let fragments = Fragmenter::fragment(&packet).unwrap();
+
+        let mut reassembler = Reassembler::new(Duration::from_millis(0));
+        assert!(reassembler.push(fragments[0].clone()).is_none());
+        assert_eq!(reassembler.len(), 1);
+
+        assert_eq!(reassembler.expired(), vec![42]);
+        assert!(reassembler.is_empty());
+    }
+}
@@ -4,4 +4,11 @@ pub use queen_io;
 
 pub mod codec;
 pub mod packet;
+pub mod crypto;
+pub mod packet_codec;
+pub mod fragment;
+pub mod reliable;
 pub mod conn;
+pub mod socks5;
+#[cfg(feature = "srv")]
+pub mod srv;
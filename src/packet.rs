@@ -1,19 +1,39 @@
 use std::io::{self, Write};
+#[cfg(feature = "gzip")]
+use std::io::Read;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::str;
+
+use crate::crypto::{self, PacketCrypto};
+#[cfg(test)]
+use crate::crypto::{Key, Key128, Key256};
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
 
 pub const MTU: u32 = 1400;
-#[derive(Debug, Default)]
+pub const HEADER_SIZE: usize = 8;
+pub const MAX_CHAN_LEN: usize = 255;
+/// Upper bound of a decompressed body, guards against zip bombs
+pub const MAX_DECOMPRESSED_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Packet {
     pub header: Header,
     pub chan: String,
     pub body: Vec<u8>
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Header {
     bytes: [u8; 8],
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum Type {
     Non,
@@ -22,7 +42,8 @@ pub enum Type {
     Rst
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum Compress {
     None,
@@ -30,7 +51,8 @@ pub enum Compress {
     Gzip
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum Crypto {
     None,
@@ -39,6 +61,225 @@ pub enum Crypto {
     ChaCha20Poly1305
 }
 
+/// Well-known values of the header's `content_type` byte. Values from
+/// `ContentType::APPLICATION` (0x80) up are left to applications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(u8)]
+pub enum ContentType {
+    Raw,
+    Utf8,
+    Json,
+    MsgPack,
+    Cbor,
+    Protobuf,
+    Nson
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError {
+    Truncated,
+    TooLarge(usize),
+    InvalidChan(str::Utf8Error),
+    Compress(String),
+    MissingKey(u8),
+    InvalidKeyId(u8),
+    EncryptionFailed,
+    DecryptionFailed,
+    InvalidVersion(u8),
+    InvalidType(u8),
+    InvalidCompress(u8),
+    InvalidCrypto(u8),
+    Invalid(PacketValidationError)
+}
+
+/// Rules checked by `Packet::validate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketValidationError {
+    InvalidVersion(u8),
+    /// chan longer than `MAX_CHAN_LEN` bytes
+    ChanTooLong(usize),
+    /// bits of the type byte other than `Type` and `Header::FRAGMENT`
+    ReservedBits(u8),
+    /// unknown compress nibble
+    InvalidCompress(u8),
+    /// unknown crypto nibble
+    InvalidCrypto(u8),
+    /// encoded size above `MTU` on a packet that is not a fragment
+    TooLarge(usize)
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::Truncated => write!(f, "truncated packet"),
+            PacketError::TooLarge(len) => write!(f, "packet too large: {}", len),
+            PacketError::InvalidChan(err) => write!(f, "invalid chan: {}", err),
+            PacketError::Compress(err) => write!(f, "compress: {}", err),
+            PacketError::MissingKey(id) => write!(f, "missing key: {}", id),
+            PacketError::InvalidKeyId(id) => write!(f, "invalid key id: {}", id),
+            PacketError::EncryptionFailed => write!(f, "encryption failed"),
+            PacketError::DecryptionFailed => write!(f, "decryption failed"),
+            PacketError::InvalidVersion(v) => write!(f, "invalid version: {}", v),
+            PacketError::InvalidType(v) => write!(f, "invalid type: {}", v),
+            PacketError::InvalidCompress(v) => write!(f, "invalid compress: {}", v),
+            PacketError::InvalidCrypto(v) => write!(f, "invalid crypto: {}", v),
+            PacketError::Invalid(err) => write!(f, "invalid packet: {}", err)
+        }
+    }
+}
+
+impl error::Error for PacketError {}
+
+impl fmt::Display for PacketValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketValidationError::InvalidVersion(v) => write!(f, "invalid version: {}", v),
+            PacketValidationError::ChanTooLong(len) => write!(f, "chan too long: {}", len),
+            PacketValidationError::ReservedBits(v) => write!(f, "reserved bits set: {:#010b}", v),
+            PacketValidationError::InvalidCompress(v) => write!(f, "invalid compress: {}", v),
+            PacketValidationError::InvalidCrypto(v) => write!(f, "invalid crypto: {}", v),
+            PacketValidationError::TooLarge(len) => write!(f, "packet too large: {}", len)
+        }
+    }
+}
+
+impl error::Error for PacketValidationError {}
+
+impl From<PacketValidationError> for PacketError {
+    fn from(err: PacketValidationError) -> PacketError {
+        PacketError::Invalid(err)
+    }
+}
+
+impl From<PacketError> for io::Error {
+    fn from(err: PacketError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct HeaderFields {
+    message_id: u16,
+    r#type: Type,
+    code: u8,
+    compress: Compress,
+    crypto: Crypto,
+    content_type: u8,
+    ext: u8,
+    #[serde(default)]
+    fragment: bool
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Header {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+
+        let fields = HeaderFields {
+            message_id: self.message_id(),
+            r#type: self.r#type().map_err(S::Error::custom)?,
+            code: self.code(),
+            compress: self.compress().map_err(S::Error::custom)?,
+            crypto: self.crypto().map_err(S::Error::custom)?,
+            content_type: self.content_type(),
+            ext: self.ext(),
+            fragment: self.fragment()
+        };
+
+        fields.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Header {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields = HeaderFields::deserialize(deserializer)?;
+
+        let mut header = Header::new();
+        header.set_message_id(fields.message_id);
+        header.set_type(fields.r#type);
+        header.set_code(fields.code);
+        header.set_compress(fields.compress);
+        header.set_crypto(fields.crypto);
+        header.set_content_type(fields.content_type);
+        header.set_ext(fields.ext);
+        header.set_fragment(fields.fragment);
+
+        Ok(header)
+    }
+}
+
+impl TryFrom<u8> for Type {
+    type Error = PacketError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(Type::Non),
+            1 => Ok(Type::Con),
+            2 => Ok(Type::Ack),
+            3 => Ok(Type::Rst),
+            _ => Err(PacketError::InvalidType(v))
+        }
+    }
+}
+
+impl TryFrom<u8> for Compress {
+    type Error = PacketError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(Compress::None),
+            1 => Ok(Compress::Zstd),
+            2 => Ok(Compress::Gzip),
+            _ => Err(PacketError::InvalidCompress(v))
+        }
+    }
+}
+
+impl TryFrom<u8> for Crypto {
+    type Error = PacketError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(Crypto::None),
+            1 => Ok(Crypto::Aes128Gcm),
+            2 => Ok(Crypto::Aes256Gcm),
+            3 => Ok(Crypto::ChaCha20Poly1305),
+            _ => Err(PacketError::InvalidCrypto(v))
+        }
+    }
+}
+
+impl ContentType {
+    /// First value reserved for application-defined content types
+    pub const APPLICATION: u8 = 0x80;
+
+    pub fn from_u8(v: u8) -> Option<ContentType> {
+        match v {
+            0 => Some(ContentType::Raw),
+            1 => Some(ContentType::Utf8),
+            2 => Some(ContentType::Json),
+            3 => Some(ContentType::MsgPack),
+            4 => Some(ContentType::Cbor),
+            5 => Some(ContentType::Protobuf),
+            6 => Some(ContentType::Nson),
+            _ => None
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl From<ContentType> for u8 {
+    fn from(c: ContentType) -> u8 {
+        c as u8
+    }
+}
+
 impl Packet {
     pub fn new() -> Self {
         let header = Header::new();
@@ -50,26 +291,211 @@ impl Packet {
         }
     }
 
-    pub fn from_bytes(_bytes: &[u8]) {
+    /// Decodes a packet, decrypting its body with `crypto` when the header
+    /// asks for it.
+    pub fn from_bytes(
+        bytes: &[u8],
+        jumbo: bool,
+        crypto: Option<&PacketCrypto>
+    ) -> Result<Packet, PacketError> {
+        if !jumbo && bytes.len() > MTU as usize {
+            return Err(PacketError::TooLarge(bytes.len()))
+        }
+
+        if bytes.len() < HEADER_SIZE + 1 {
+            return Err(PacketError::Truncated)
+        }
+
+        let mut header_bytes = [0u8; HEADER_SIZE];
+        header_bytes.copy_from_slice(&bytes[..HEADER_SIZE]);
+        let header = Header::from_bytes(header_bytes)?;
+
+        let rest = &bytes[HEADER_SIZE..];
+        let end = match rest.iter().position(|b| *b == 0) {
+            Some(end) => end,
+            None => return Err(PacketError::Truncated)
+        };
+
+        if end > MAX_CHAN_LEN {
+            return Err(PacketValidationError::ChanTooLong(end).into())
+        }
+
+        let chan = str::from_utf8(&rest[..end])
+            .map_err(PacketError::InvalidChan)?
+            .to_string();
+
+        let body = decrypt(&header, &chan, crypto, &rest[end + 1..])?;
+        let body = decompress(&header, &body)?;
 
+        Ok(Packet {
+            header,
+            chan,
+            body
+        })
     }
 
-    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+    /// Checks the packet against the wire format rules without encoding it.
+    /// The size rule uses the body as is, before compression, plus the nonce
+    /// and tag added by encryption.
+    pub fn validate(&self) -> Result<(), PacketValidationError> {
+        if self.header.version() != Header::VERSION {
+            return Err(PacketValidationError::InvalidVersion(self.header.version()))
+        }
+
+        if self.chan.len() > MAX_CHAN_LEN {
+            return Err(PacketValidationError::ChanTooLong(self.chan.len()))
+        }
+
+        let reserved = self.header.bytes[3] & !0b11 & !Header::FRAGMENT;
+        if reserved != 0 {
+            return Err(PacketValidationError::ReservedBits(reserved))
+        }
+
+        self.header.compress().map_err(|_|
+            PacketValidationError::InvalidCompress(self.header.bytes[5] >> 4)
+        )?;
+
+        let cipher = self.header.crypto().map_err(|_|
+            PacketValidationError::InvalidCrypto(self.header.bytes[5] & 0b00001111)
+        )?;
+
+        let mut len = HEADER_SIZE + self.chan.len() + 1 + self.body.len();
+        if cipher != Crypto::None {
+            len += crypto::NONCE_SIZE + crypto::TAG_SIZE;
+        }
+
+        if len > MTU as usize && !self.header.more_fragments() {
+            return Err(PacketValidationError::TooLarge(len))
+        }
+
+        Ok(())
+    }
+
+    /// Encodes the packet. When the header asks for encryption, the body is
+    /// encrypted with the active key of `crypto` and its id is written to
+    /// the header.
+    ///
+    /// Packets encoding to more than `MTU` bytes are rejected unless they
+    /// are fragments, split them with `fragment::Fragmenter` first.
+    pub fn to_bytes(&self, crypto: Option<&PacketCrypto>) -> io::Result<Vec<u8>> {
+        if self.chan.len() > MAX_CHAN_LEN {
+            return Err(PacketError::from(PacketValidationError::ChanTooLong(self.chan.len())).into())
+        }
+
+        let mut header = self.header.clone();
+
+        if header.crypto()? != Crypto::None {
+            if let Some(crypto) = crypto {
+                header.set_key_id(crypto.active());
+            }
+        }
+
         let mut buffer = Vec::new();
 
-        buffer.extend(&self.header.bytes);
+        buffer.extend(&header.bytes);
         buffer.write_all(self.chan.as_bytes())?;
         buffer.write_all(&[0])?;
 
-        //
-        buffer.extend(&self.body);
+        let body = compress(&header, &self.body)?;
+        let body = encrypt(&header, &self.chan, crypto, &body)?;
+        buffer.extend(&body);
+
+        if buffer.len() > MTU as usize && !header.more_fragments() {
+            return Err(PacketError::from(PacketValidationError::TooLarge(buffer.len())).into())
+        }
 
         Ok(buffer)
     }
 }
 
+#[allow(unreachable_patterns)]
+pub(crate) fn compress(header: &Header, body: &[u8]) -> io::Result<Vec<u8>> {
+    match header.compress()? {
+        Compress::None => Ok(body.to_vec()),
+        #[cfg(feature = "zstd")]
+        Compress::Zstd => zstd::block::compress(body, header.compress_level() as i32),
+        #[cfg(feature = "gzip")]
+        Compress::Gzip => {
+            // level 0 is the default, 1 is fast, 9 is best; mtime stays 0 so
+            // output is deterministic
+            let level = match header.compress_level() {
+                0 => flate2::Compression::default(),
+                level => flate2::Compression::new(u32::from(level).min(9))
+            };
+
+            let mut encoder = flate2::GzBuilder::new().mtime(0).write(Vec::new(), level);
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        m => Err(io::Error::new(io::ErrorKind::Unsupported, format!("unsupported compress: {:?}", m)))
+    }
+}
+
+// the header and chan are authenticated along with the body
+fn aad(header: &Header, chan: &str) -> Vec<u8> {
+    let mut aad = header.bytes.to_vec();
+    aad.extend_from_slice(chan.as_bytes());
+    aad
+}
+
+fn encrypt(header: &Header, chan: &str, crypto: Option<&PacketCrypto>, body: &[u8]) -> Result<Vec<u8>, PacketError> {
+    let c = header.crypto()?;
+
+    if c == Crypto::None {
+        return Ok(body.to_vec())
+    }
+
+    let key_id = header.key_id();
+    let key = crypto.and_then(|crypto| crypto.key(c, key_id)).ok_or(PacketError::MissingKey(key_id))?;
+
+    crypto::seal(key, &aad(header, chan), body)
+}
+
+fn decrypt(header: &Header, chan: &str, crypto: Option<&PacketCrypto>, body: &[u8]) -> Result<Vec<u8>, PacketError> {
+    let c = header.crypto()?;
+
+    if c == Crypto::None {
+        return Ok(body.to_vec())
+    }
+
+    let key_id = header.key_id();
+    let key = crypto.and_then(|crypto| crypto.key(c, key_id)).ok_or(PacketError::MissingKey(key_id))?;
+
+    crypto::open(key, &aad(header, chan), body)
+}
+
+#[allow(unreachable_patterns)]
+pub(crate) fn decompress(header: &Header, body: &[u8]) -> Result<Vec<u8>, PacketError> {
+    match header.compress()? {
+        Compress::None => Ok(body.to_vec()),
+        #[cfg(feature = "zstd")]
+        Compress::Zstd => zstd::block::decompress(body, MAX_DECOMPRESSED_SIZE)
+            .map_err(|err| PacketError::Compress(err.to_string())),
+        #[cfg(feature = "gzip")]
+        Compress::Gzip => {
+            let mut buf = Vec::new();
+
+            flate2::read::GzDecoder::new(body)
+                .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                .read_to_end(&mut buf)
+                .map_err(|err| PacketError::Compress(err.to_string()))?;
+
+            if buf.len() > MAX_DECOMPRESSED_SIZE {
+                return Err(PacketError::Compress("decompressed body too large".to_string()))
+            }
+
+            Ok(buf)
+        }
+        m => Err(PacketError::Compress(format!("unsupported compress: {:?}", m)))
+    }
+}
+
 impl Header {
     pub const VERSION: u8 = 1;
+    /// `ext` bit set on every fragment but the last
+    pub const MORE_FRAGMENTS: u8 = 0b10000000;
+    /// type byte bit set on every fragment, `code` then holds the fragment index
+    pub const FRAGMENT: u8 = 0b10000000;
 
     pub fn new() -> Self {
         let mut header = Header::default();
@@ -79,10 +505,24 @@ impl Header {
         header
     }
 
-    pub fn from_bytes(_bytes: [u8; 8]) {
-        todo!()
+    pub fn from_bytes(bytes: [u8; 8]) -> Result<Self, PacketError> {
+        if bytes[2] != Self::VERSION {
+            return Err(PacketError::InvalidVersion(bytes[2]))
+        }
+
+        let header = Header { bytes };
+
+        header.r#type()?;
+        header.compress()?;
+        header.crypto()?;
+
+        Ok(header)
     }
 
+    /// # Safety
+    ///
+    /// `bytes` is not validated, so the getters returning `Result` may fail
+    /// and `Packet::to_bytes` may reject the header.
     pub unsafe fn from_bytes_unchecked(bytes: [u8; 8]) -> Self {
         Header { bytes }
     }
@@ -97,12 +537,29 @@ impl Header {
         self.bytes[..2].copy_from_slice(&message_id.to_le_bytes());
     }
 
-    pub fn r#type(&self) -> Type {
-        todo!()
+    pub fn version(&self) -> u8 {
+        self.bytes[2]
+    }
+
+    pub fn r#type(&self) -> Result<Type, PacketError> {
+        Type::try_from(self.bytes[3] & !Self::FRAGMENT)
     }
 
     pub fn set_type(&mut self, r#type: Type) {
-        self.bytes[3] = r#type as u8;
+        self.bytes[3] &= Self::FRAGMENT;
+        self.bytes[3] |= r#type as u8;
+    }
+
+    pub fn fragment(&self) -> bool {
+        self.bytes[3] & Self::FRAGMENT != 0
+    }
+
+    pub fn set_fragment(&mut self, fragment: bool) {
+        if fragment {
+            self.bytes[3] |= Self::FRAGMENT;
+        } else {
+            self.bytes[3] &= !Self::FRAGMENT;
+        }
     }
 
     pub fn code(&self) -> u8 {
@@ -113,8 +570,8 @@ impl Header {
         self.bytes[4] = code;
     }
 
-    pub fn compress(&self) -> Compress {
-        todo!()
+    pub fn compress(&self) -> Result<Compress, PacketError> {
+        Compress::try_from(self.bytes[5] >> 4)
     }
 
     pub fn set_compress(&mut self, m: Compress) {
@@ -122,8 +579,8 @@ impl Header {
         self.bytes[5] |= (m as u8) << 4;
     }
 
-    pub fn crypto(&self) -> Crypto {
-        todo!()
+    pub fn crypto(&self) -> Result<Crypto, PacketError> {
+        Crypto::try_from(self.bytes[5] & 0b00001111)
     }
 
     pub fn set_crypto(&mut self, m: Crypto) {
@@ -131,12 +588,33 @@ impl Header {
         self.bytes[5] |= m as u8;
     }
 
+    /// Compression level, kept in the low nibble of `ext`. 0 selects the
+    /// codec's default level.
+    pub fn compress_level(&self) -> u8 {
+        self.bytes[7] & 0b00001111
+    }
+
+    pub fn set_compress_level(&mut self, level: u8) {
+        self.bytes[7] &= 0b11110000;
+        self.bytes[7] |= level & 0b00001111;
+    }
+
+    /// Id of the key the body is encrypted with, kept in bits 4-6 of `ext`
+    pub fn key_id(&self) -> u8 {
+        (self.bytes[7] >> 4) & 0b111
+    }
+
+    pub fn set_key_id(&mut self, key_id: u8) {
+        self.bytes[7] &= 0b10001111;
+        self.bytes[7] |= (key_id & 0b111) << 4;
+    }
+
     pub fn content_type(&self) -> u8 {
        self.bytes[6]
     }
 
-    pub fn set_content_type(&mut self, c: u8) {
-        self.bytes[6] = c;
+    pub fn set_content_type(&mut self, c: impl Into<u8>) {
+        self.bytes[6] = c.into();
     }
 
     pub fn ext(&self) -> u8 {
@@ -147,11 +625,34 @@ impl Header {
         self.bytes[7] = ext;
     }
 
+    pub fn more_fragments(&self) -> bool {
+        self.bytes[7] & Self::MORE_FRAGMENTS != 0
+    }
+
+    pub fn set_more_fragments(&mut self, more: bool) {
+        if more {
+            self.bytes[7] |= Self::MORE_FRAGMENTS;
+        } else {
+            self.bytes[7] &= !Self::MORE_FRAGMENTS;
+        }
+    }
+
     pub fn bytes(&self) -> [u8; 8] {
         self.bytes
     }
 }
 
+#[cfg(test)]
+pub(crate) fn test_packet(message_id: u16, r#type: Type, chan: &str, body: &[u8]) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.set_message_id(message_id);
+    packet.header.set_type(r#type);
+    packet.chan = chan.to_string();
+    packet.body = body.to_vec();
+
+    packet
+}
+
 #[test]
 fn set_compress_and_crypto() {
     let mut packet = Packet::new();
@@ -169,6 +670,520 @@ fn set_compress_and_crypto() {
     assert_eq!(packet.header.bytes[5], 0);
 
     packet.header.set_message_id(123);
+    assert_eq!(packet.header.message_id(), 123);
+    assert_eq!(packet.header.bytes[5], 0);
+}
+
+#[test]
+fn header_round_trip() {
+    let compress = [Compress::None, Compress::Zstd, Compress::Gzip];
+    let crypto = [Crypto::None, Crypto::Aes128Gcm, Crypto::Aes256Gcm, Crypto::ChaCha20Poly1305];
+    let types = [Type::Non, Type::Con, Type::Ack, Type::Rst];
+
+    for (i, r#type) in types.iter().enumerate() {
+        for m in compress.iter() {
+            for c in crypto.iter() {
+                let mut header = Header::new();
+                header.set_message_id(i as u16);
+                header.set_type(*r#type);
+                header.set_compress(*m);
+                header.set_crypto(*c);
+
+                let header = Header::from_bytes(header.bytes()).unwrap();
+
+                assert_eq!(header.message_id(), i as u16);
+                assert_eq!(header.r#type().unwrap(), *r#type);
+                assert_eq!(header.compress().unwrap(), *m);
+                assert_eq!(header.crypto().unwrap(), *c);
+            }
+        }
+    }
+}
+
+#[test]
+fn header_fragment() {
+    let mut header = Header::new();
+    header.set_type(Type::Ack);
+    header.set_fragment(true);
+    header.set_type(Type::Con);
+
+    let header = Header::from_bytes(header.bytes()).unwrap();
+    assert!(header.fragment());
+    assert_eq!(header.r#type().unwrap(), Type::Con);
+
+    let mut header = header;
+    header.set_fragment(false);
+    assert!(!header.fragment());
+    assert_eq!(header.r#type().unwrap(), Type::Con);
+}
 
-    panic!("{:?}", packet)
+#[test]
+fn header_from_bytes_invalid() {
+    let mut header = Header::new();
+    header.bytes[2] = 2;
+    assert_eq!(Header::from_bytes(header.bytes()).unwrap_err(), PacketError::InvalidVersion(2));
+
+    let mut header = Header::new();
+    header.bytes[3] = 4;
+    assert_eq!(Header::from_bytes(header.bytes()).unwrap_err(), PacketError::InvalidType(4));
+
+    let mut header = Header::new();
+    header.bytes[5] = 3 << 4;
+    assert_eq!(Header::from_bytes(header.bytes()).unwrap_err(), PacketError::InvalidCompress(3));
+
+    let mut header = Header::new();
+    header.bytes[5] = 4;
+    assert_eq!(Header::from_bytes(header.bytes()).unwrap_err(), PacketError::InvalidCrypto(4));
+}
+
+#[test]
+fn content_type() {
+    for v in 0..=6u8 {
+        assert_eq!(ContentType::from_u8(v).unwrap().as_u8(), v);
+    }
+
+    assert_eq!(ContentType::from_u8(7), None);
+    assert_eq!(ContentType::from_u8(ContentType::APPLICATION), None);
+
+    let mut header = Header::new();
+    header.set_content_type(ContentType::Json);
+    assert_eq!(ContentType::from_u8(header.content_type()), Some(ContentType::Json));
+
+    header.set_content_type(ContentType::APPLICATION + 1);
+    assert_eq!(header.content_type(), 0x81);
+}
+
+#[test]
+fn packet_round_trip() {
+    let chans = ["", "a", "hello/world", "频道"];
+    let bodies: [&[u8]; 4] = [b"", b"\0", b"123\x00456", &[0xff; 1024]];
+
+    for chan in chans.iter() {
+        for body in bodies.iter() {
+            let mut packet = Packet::new();
+            packet.header.set_message_id(456);
+            packet.header.set_type(Type::Con);
+            packet.header.set_code(7);
+            packet.header.set_content_type(2);
+            packet.chan = chan.to_string();
+            packet.body = body.to_vec();
+
+            let bytes = packet.to_bytes(None).unwrap();
+            assert_eq!(Packet::from_bytes(&bytes, false, None).unwrap(), packet);
+        }
+    }
+}
+
+#[test]
+fn packet_from_bytes_invalid() {
+    assert_eq!(Packet::from_bytes(&[], false, None).unwrap_err(), PacketError::Truncated);
+
+    let header = Header::new().bytes();
+    assert_eq!(Packet::from_bytes(&header, false, None).unwrap_err(), PacketError::Truncated);
+
+    let mut bytes = header.to_vec();
+    bytes.extend_from_slice(b"chan");
+    assert_eq!(Packet::from_bytes(&bytes, false, None).unwrap_err(), PacketError::Truncated);
+
+    let mut bytes = header.to_vec();
+    bytes.extend_from_slice(&[0xff, 0xfe, 0]);
+    assert!(matches!(Packet::from_bytes(&bytes, false, None), Err(PacketError::InvalidChan(_))));
+
+    // fragments are exempt from the MTU check
+    let mut packet = Packet::new();
+    packet.header.set_more_fragments(true);
+    packet.body = vec![0; MTU as usize];
+    let bytes = packet.to_bytes(None).unwrap();
+    assert_eq!(Packet::from_bytes(&bytes, false, None).unwrap_err(), PacketError::TooLarge(bytes.len()));
+    assert_eq!(Packet::from_bytes(&bytes, true, None).unwrap(), packet);
+
+    let mut bytes = header.to_vec();
+    bytes.extend_from_slice(&[b'a'; MAX_CHAN_LEN + 1]);
+    bytes.push(0);
+    assert_eq!(
+        Packet::from_bytes(&bytes, false, None).unwrap_err(),
+        PacketError::Invalid(PacketValidationError::ChanTooLong(MAX_CHAN_LEN + 1))
+    );
+}
+
+#[test]
+fn packet_validate() {
+    let mut packet = Packet::new();
+    packet.chan = "a".repeat(MAX_CHAN_LEN);
+    packet.body = vec![0; MTU as usize - HEADER_SIZE - MAX_CHAN_LEN - 1];
+    assert_eq!(packet.validate(), Ok(()));
+
+    packet.body.push(0);
+    assert_eq!(packet.validate(), Err(PacketValidationError::TooLarge(MTU as usize + 1)));
+
+    packet.header.set_more_fragments(true);
+    assert_eq!(packet.validate(), Ok(()));
+
+    packet.chan.push('a');
+    assert_eq!(packet.validate(), Err(PacketValidationError::ChanTooLong(MAX_CHAN_LEN + 1)));
+    assert!(packet.to_bytes(None).is_err());
+
+    let mut bytes = Header::new().bytes();
+    bytes[3] = 0b100 | Type::Con as u8;
+    let mut packet = Packet::new();
+    packet.header = unsafe { Header::from_bytes_unchecked(bytes) };
+    assert_eq!(packet.validate(), Err(PacketValidationError::ReservedBits(0b100)));
+
+    bytes[2] = 0;
+    packet.header = unsafe { Header::from_bytes_unchecked(bytes) };
+    assert_eq!(packet.validate(), Err(PacketValidationError::InvalidVersion(0)));
+
+    let mut packet = Packet::new();
+    packet.body = vec![0; MTU as usize + 1];
+    assert_eq!(packet.to_bytes(None).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    // the whole frame counts, not only the body
+    let mut packet = Packet::new();
+    packet.body = vec![0; MTU as usize - HEADER_SIZE - 1];
+    assert_eq!(packet.to_bytes(None).unwrap().len(), MTU as usize);
+
+    packet.chan = "a".to_string();
+    assert_eq!(packet.validate(), Err(PacketValidationError::TooLarge(MTU as usize + 1)));
+    assert_eq!(packet.to_bytes(None).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn packet_validate_crypto() {
+    let crypto = PacketCrypto::new(0, Key::Aes128Gcm(Key128([7; 16]))).unwrap();
+    let overhead = crypto::NONCE_SIZE + crypto::TAG_SIZE;
+
+    let mut packet = Packet::new();
+    packet.header.set_crypto(Crypto::Aes128Gcm);
+    packet.body = vec![0; MTU as usize - HEADER_SIZE - 1 - overhead];
+    assert_eq!(packet.validate(), Ok(()));
+    assert_eq!(packet.to_bytes(Some(&crypto)).unwrap().len(), MTU as usize);
+
+    packet.body.push(0);
+    assert_eq!(packet.validate(), Err(PacketValidationError::TooLarge(MTU as usize + 1)));
+    assert!(packet.to_bytes(Some(&crypto)).is_err());
+}
+
+#[test]
+fn packet_validate_compress_and_crypto() {
+    let mut bytes = Header::new().bytes();
+    bytes[5] = 0xff;
+    let mut packet = Packet::new();
+    packet.header = unsafe { Header::from_bytes_unchecked(bytes) };
+    assert_eq!(packet.validate(), Err(PacketValidationError::InvalidCompress(15)));
+
+    bytes[5] = 0x0f;
+    packet.header = unsafe { Header::from_bytes_unchecked(bytes) };
+    assert_eq!(packet.validate(), Err(PacketValidationError::InvalidCrypto(15)));
+
+    bytes[5] = 0;
+    packet.header = unsafe { Header::from_bytes_unchecked(bytes) };
+    assert_eq!(packet.validate(), Ok(()));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn packet_serde_json() {
+    let types = [Type::Non, Type::Con, Type::Ack, Type::Rst];
+    let compress = [Compress::None, Compress::Zstd, Compress::Gzip];
+    let crypto = [Crypto::None, Crypto::Aes128Gcm, Crypto::Aes256Gcm, Crypto::ChaCha20Poly1305];
+
+    for r#type in types.iter() {
+        for m in compress.iter() {
+            for c in crypto.iter() {
+                let mut packet = Packet::new();
+                packet.header.set_message_id(123);
+                packet.header.set_type(*r#type);
+                packet.header.set_compress(*m);
+                packet.header.set_crypto(*c);
+                packet.header.set_content_type(2);
+                packet.chan = "hello".to_string();
+                packet.body = vec![1, 2, 3, 0, 255];
+
+                let json = serde_json::to_string(&packet).unwrap();
+                let packet2: Packet = serde_json::from_str(&json).unwrap();
+
+                assert_eq!(packet, packet2);
+            }
+        }
+    }
+
+    let json = serde_json::to_value(&Packet::new().header).unwrap();
+    assert_eq!(json["type"], "Non");
+    assert_eq!(json["compress"], "None");
+}
+
+#[test]
+fn unsupported_compress() {
+    let mut packet = Packet::new();
+    packet.header.set_compress(Compress::Zstd);
+    packet.body = b"hello".to_vec();
+
+    if cfg!(feature = "zstd") {
+        return
+    }
+
+    assert!(packet.to_bytes(None).is_err());
+
+    let mut bytes = packet.header.bytes().to_vec();
+    bytes.extend_from_slice(b"\0hello");
+    assert!(matches!(Packet::from_bytes(&bytes, false, None), Err(PacketError::Compress(_))));
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_round_trip() {
+    for level in [0, 1, 3, 15].iter() {
+        let mut packet = Packet::new();
+        packet.header.set_compress(Compress::Zstd);
+        packet.header.set_compress_level(*level);
+        packet.chan = "zstd".to_string();
+        packet.body = b"hello world ".repeat(100);
+
+        let bytes = packet.to_bytes(None).unwrap();
+        assert!(bytes.len() < packet.body.len());
+
+        assert_eq!(Packet::from_bytes(&bytes, false, None).unwrap(), packet);
+    }
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_bomb() {
+    let mut header = Header::new();
+    header.set_compress(Compress::Zstd);
+
+    let mut bytes = header.bytes().to_vec();
+    bytes.push(0);
+    bytes.extend(zstd::block::compress(&vec![0; MAX_DECOMPRESSED_SIZE + 1], 0).unwrap());
+
+    assert!(bytes.len() < MTU as usize);
+    assert!(matches!(Packet::from_bytes(&bytes, false, None), Err(PacketError::Compress(_))));
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_round_trip() {
+    for level in [0, 1, 6, 9].iter() {
+        let mut packet = Packet::new();
+        packet.header.set_compress(Compress::Gzip);
+        packet.header.set_compress_level(*level);
+        packet.chan = "gzip".to_string();
+        packet.body = b"hello world ".repeat(100);
+
+        let bytes = packet.to_bytes(None).unwrap();
+        assert_eq!(bytes, packet.to_bytes(None).unwrap());
+
+        assert_eq!(Packet::from_bytes(&bytes, false, None).unwrap(), packet);
+
+        // the body is a plain gzip member
+        let mut body = Vec::new();
+        flate2::read::GzDecoder::new(&bytes[HEADER_SIZE + 5..]).read_to_end(&mut body).unwrap();
+        assert_eq!(body, packet.body);
+    }
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_default_level() {
+    let mut packet = Packet::new();
+    packet.header.set_compress(Compress::Gzip);
+    packet.body = b"hello world ".repeat(100);
+
+    let default = packet.to_bytes(None).unwrap();
+
+    packet.header.set_compress_level(6);
+    let level6 = packet.to_bytes(None).unwrap();
+
+    // level 0 only differs from level 6 in the ext byte
+    assert_eq!(default[HEADER_SIZE..], level6[HEADER_SIZE..]);
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_bomb() {
+    let mut header = Header::new();
+    header.set_compress(Compress::Gzip);
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(&vec![0; MAX_DECOMPRESSED_SIZE + 1]).unwrap();
+
+    let mut bytes = header.bytes().to_vec();
+    bytes.push(0);
+    bytes.extend(encoder.finish().unwrap());
+
+    assert!(matches!(Packet::from_bytes(&bytes, true, None), Err(PacketError::Compress(_))));
+}
+
+#[test]
+fn aes128gcm_round_trip() {
+    let mut crypto = PacketCrypto::new(1, Key::Aes128Gcm(Key128([7; 16]))).unwrap();
+    crypto.add_key(2, Key::Aes128Gcm(Key128([9; 16]))).unwrap();
+
+    let mut packet = Packet::new();
+    packet.header.set_crypto(Crypto::Aes128Gcm);
+    packet.chan = "secret".to_string();
+    packet.body = b"hello world".to_vec();
+
+    for key_id in [1, 2].iter() {
+        assert!(crypto.set_active(*key_id));
+        packet.header.set_key_id(*key_id);
+
+        let bytes = packet.to_bytes(Some(&crypto)).unwrap();
+        assert!(!bytes.windows(packet.body.len()).any(|w| w == &packet.body[..]));
+
+        assert_eq!(Packet::from_bytes(&bytes, false, Some(&crypto)).unwrap(), packet);
+
+        // random nonce
+        assert_ne!(bytes, packet.to_bytes(Some(&crypto)).unwrap());
+    }
+}
+
+#[test]
+fn aes128gcm_key_rotation() {
+    let sender = PacketCrypto::new(3, Key::Aes128Gcm(Key128([3; 16]))).unwrap();
+
+    let mut receiver = PacketCrypto::new(1, Key::Aes128Gcm(Key128([1; 16]))).unwrap();
+    receiver.add_key(3, Key::Aes128Gcm(Key128([3; 16]))).unwrap();
+
+    let mut packet = Packet::new();
+    packet.header.set_crypto(Crypto::Aes128Gcm);
+    packet.body = b"rotate".to_vec();
+
+    let bytes = packet.to_bytes(Some(&sender)).unwrap();
+    let packet2 = Packet::from_bytes(&bytes, false, Some(&receiver)).unwrap();
+
+    assert_eq!(packet2.header.key_id(), 3);
+    assert_eq!(packet2.body, packet.body);
+
+    receiver.remove_key(3);
+    assert_eq!(Packet::from_bytes(&bytes, false, Some(&receiver)).unwrap_err(), PacketError::MissingKey(3));
+    assert_eq!(Packet::from_bytes(&bytes, false, None).unwrap_err(), PacketError::MissingKey(3));
+}
+
+#[test]
+fn invalid_key_id() {
+    let key = Key::Aes128Gcm(Key128([3; 16]));
+    assert_eq!(PacketCrypto::new(8, key.clone()).err(), Some(PacketError::InvalidKeyId(8)));
+
+    let mut crypto = PacketCrypto::new(PacketCrypto::MAX_KEY_ID, key.clone()).unwrap();
+    assert_eq!(crypto.add_key(255, key).unwrap_err(), PacketError::InvalidKeyId(255));
+}
+
+#[test]
+fn aes128gcm_tampered() {
+    let crypto = PacketCrypto::new(0, Key::Aes128Gcm(Key128([5; 16]))).unwrap();
+
+    let mut packet = Packet::new();
+    packet.header.set_crypto(Crypto::Aes128Gcm);
+    packet.chan = "chan".to_string();
+    packet.body = b"hello".to_vec();
+
+    let bytes = packet.to_bytes(Some(&crypto)).unwrap();
+
+    let mut tampered = bytes.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert_eq!(Packet::from_bytes(&tampered, false, Some(&crypto)).unwrap_err(), PacketError::DecryptionFailed);
+
+    let mut tampered = bytes.clone();
+    tampered[0] ^= 1;
+    assert_eq!(Packet::from_bytes(&tampered, false, Some(&crypto)).unwrap_err(), PacketError::DecryptionFailed);
+
+    let other = PacketCrypto::new(0, Key::Aes128Gcm(Key128([6; 16]))).unwrap();
+    assert_eq!(Packet::from_bytes(&bytes, false, Some(&other)).unwrap_err(), PacketError::DecryptionFailed);
+}
+
+#[test]
+fn aes256gcm_and_chacha20poly1305_round_trip() {
+    let mut crypto = PacketCrypto::new(4, Key::Aes256Gcm(Key256([1; 32]))).unwrap();
+    crypto.add_key(4, Key::ChaCha20Poly1305(Key256([2; 32]))).unwrap();
+
+    for c in [Crypto::Aes256Gcm, Crypto::ChaCha20Poly1305].iter() {
+        let mut packet = Packet::new();
+        packet.header.set_crypto(*c);
+        packet.header.set_key_id(4);
+        packet.chan = "secret".to_string();
+        packet.body = b"hello world".to_vec();
+
+        let bytes = packet.to_bytes(Some(&crypto)).unwrap();
+        assert_eq!(Packet::from_bytes(&bytes, false, Some(&crypto)).unwrap(), packet);
+
+        let mut tampered = bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(Packet::from_bytes(&tampered, false, Some(&crypto)).unwrap_err(), PacketError::DecryptionFailed);
+    }
+
+    // the same key id under another algorithm is a different key
+    let mut packet = Packet::new();
+    packet.header.set_crypto(Crypto::Aes128Gcm);
+    assert!(packet.to_bytes(Some(&crypto)).is_err());
+}
+
+#[cfg(test)]
+mod prop {
+    use proptest::prelude::*;
+
+    use super::{Packet, Type, Crypto, PacketCrypto, Key, Key128, MTU, HEADER_SIZE};
+    use crate::crypto::{NONCE_SIZE, TAG_SIZE};
+
+    // leaves room for the longest generated chan and the AEAD overhead
+    const MAX_BODY: usize = MTU as usize - HEADER_SIZE - 4 * 63 - 1 - NONCE_SIZE - TAG_SIZE;
+
+    fn arb_type() -> impl Strategy<Value = Type> {
+        prop_oneof![Just(Type::Non), Just(Type::Con), Just(Type::Ack), Just(Type::Rst)]
+    }
+
+    fn arb_packet() -> impl Strategy<Value = Packet> {
+        (
+            any::<u16>(),
+            arb_type(),
+            any::<u8>(),
+            any::<u8>(),
+            0u8..16,
+            any::<bool>(),
+            "[^\u{0}]{0,63}",
+            prop::collection::vec(any::<u8>(), 0..=MAX_BODY)
+        ).prop_map(|(message_id, r#type, code, content_type, level, more, chan, body)| {
+            let mut packet = Packet::new();
+            packet.header.set_message_id(message_id);
+            packet.header.set_type(r#type);
+            packet.header.set_code(code);
+            packet.header.set_content_type(content_type);
+            packet.header.set_compress_level(level);
+            packet.header.set_more_fragments(more);
+            packet.chan = chan;
+            packet.body = body;
+            packet
+        })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(10_000))]
+
+        #[test]
+        fn packet_round_trip(packet in arb_packet()) {
+            let bytes = packet.to_bytes(None).unwrap();
+            prop_assert_eq!(Packet::from_bytes(&bytes, true, None).unwrap(), packet);
+        }
+
+        #[test]
+        fn packet_round_trip_encrypted(mut packet in arb_packet()) {
+            let crypto = PacketCrypto::new(0, Key::Aes128Gcm(Key128([9; 16]))).unwrap();
+            packet.header.set_crypto(Crypto::Aes128Gcm);
+
+            let bytes = packet.to_bytes(Some(&crypto)).unwrap();
+            prop_assert_eq!(Packet::from_bytes(&bytes, true, Some(&crypto)).unwrap(), packet);
+        }
+
+        #[test]
+        fn packet_byte_mutation(packet in arb_packet(), index in any::<prop::sample::Index>(), flip in 1u8..) {
+            let mut bytes = packet.to_bytes(None).unwrap();
+            let i = index.index(bytes.len());
+            bytes[i] ^= flip;
+
+            // a single changed byte must never decode back to the same packet
+            if let Ok(decoded) = Packet::from_bytes(&bytes, true, None) {
+                prop_assert_ne!(decoded, packet);
+            }
+        }
+    }
 }
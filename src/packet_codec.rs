@@ -0,0 +1,162 @@
+use std::io;
+
+use crate::packet::Packet;
+use crate::crypto::PacketCrypto;
+
+/// Framing of a `Packet` for a particular transport.
+pub trait PacketCodec {
+    fn encode(&self, packet: &Packet) -> io::Result<Vec<u8>>;
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<Packet>;
+}
+
+/// The plain datagram layout of `Packet::to_bytes` and `Packet::from_bytes`.
+#[derive(Clone, Default)]
+pub struct RawPacketCodec {
+    /// Accept packets larger than `MTU`
+    pub jumbo: bool,
+    pub crypto: Option<PacketCrypto>
+}
+
+impl RawPacketCodec {
+    pub fn new(jumbo: bool, crypto: Option<PacketCrypto>) -> Self {
+        RawPacketCodec {
+            jumbo,
+            crypto
+        }
+    }
+}
+
+impl PacketCodec for RawPacketCodec {
+    fn encode(&self, packet: &Packet) -> io::Result<Vec<u8>> {
+        packet.to_bytes(self.crypto.as_ref())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<Packet> {
+        Ok(Packet::from_bytes(bytes, self.jumbo, self.crypto.as_ref())?)
+    }
+}
+
+/// Prefixes the inner encoding with its length as a 4-byte LE u32, for
+/// stream transports such as TCP.
+#[derive(Clone, Default)]
+pub struct LengthPrefixedPacketCodec<C: PacketCodec = RawPacketCodec> {
+    pub inner: C
+}
+
+impl<C: PacketCodec> LengthPrefixedPacketCodec<C> {
+    pub fn new(inner: C) -> Self {
+        LengthPrefixedPacketCodec {
+            inner
+        }
+    }
+}
+
+impl<C: PacketCodec> PacketCodec for LengthPrefixedPacketCodec<C> {
+    fn encode(&self, packet: &Packet) -> io::Result<Vec<u8>> {
+        let bytes = self.inner.encode(packet)?;
+
+        let mut buf = Vec::with_capacity(4 + bytes.len());
+        buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        buf.extend(bytes);
+
+        Ok(buf)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<Packet> {
+        if bytes.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing length prefix"))
+        }
+
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&bytes[..4]);
+        let len = u32::from_le_bytes(len) as usize;
+
+        let payload = &bytes[4..];
+        if payload.len() != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("length prefix {} does not match payload length {}", len, payload.len())
+            ))
+        }
+
+        self.inner.decode(payload)
+    }
+}
+
+/// Passes bodies through untouched: `encode` returns the body and `decode`
+/// wraps the bytes in a default packet. Useful in tests.
+#[derive(Clone, Copy, Default)]
+pub struct NoopPacketCodec;
+
+impl PacketCodec for NoopPacketCodec {
+    fn encode(&self, packet: &Packet) -> io::Result<Vec<u8>> {
+        Ok(packet.body.clone())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<Packet> {
+        let mut packet = Packet::new();
+        packet.body = bytes.to_vec();
+
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::packet::{Type, Crypto, test_packet};
+    use crate::crypto::{PacketCrypto, Key, Key256};
+
+    use super::{PacketCodec, RawPacketCodec, LengthPrefixedPacketCodec, NoopPacketCodec};
+
+    #[test]
+    fn raw() {
+        let codec = RawPacketCodec::default();
+
+        let packet = test_packet(7, Type::Con, "codec", b"hello");
+        let bytes = codec.encode(&packet).unwrap();
+
+        assert_eq!(bytes, packet.to_bytes(None).unwrap());
+        assert_eq!(codec.decode(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn raw_with_crypto() {
+        let crypto = PacketCrypto::new(1, Key::ChaCha20Poly1305(Key256([3; 32]))).unwrap();
+        let codec = RawPacketCodec::new(false, Some(crypto));
+
+        let mut packet = test_packet(7, Type::Con, "codec", b"hello");
+        packet.header.set_crypto(Crypto::ChaCha20Poly1305);
+        packet.header.set_key_id(1);
+
+        let bytes = codec.encode(&packet).unwrap();
+        assert_eq!(codec.decode(&bytes).unwrap(), packet);
+        assert!(RawPacketCodec::default().decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn length_prefixed() {
+        let codec = LengthPrefixedPacketCodec::<RawPacketCodec>::default();
+
+        let packet = test_packet(7, Type::Con, "codec", b"hello");
+        let bytes = codec.encode(&packet).unwrap();
+
+        assert_eq!(bytes[..4], ((bytes.len() - 4) as u32).to_le_bytes());
+        assert_eq!(bytes[4..], packet.to_bytes(None).unwrap()[..]);
+        assert_eq!(codec.decode(&bytes).unwrap(), packet);
+
+        assert!(codec.decode(&bytes[..3]).is_err());
+        assert!(codec.decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn noop() {
+        let codec = NoopPacketCodec;
+
+        let packet = test_packet(7, Type::Con, "codec", b"hello");
+        let bytes = codec.encode(&packet).unwrap();
+
+        assert_eq!(bytes, packet.body);
+        assert_eq!(codec.decode(&bytes).unwrap().body, packet.body);
+    }
+}
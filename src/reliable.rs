@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::packet::{Packet, Type};
+use crate::packet_codec::{PacketCodec, RawPacketCodec};
+
+pub const DEFAULT_RTO: Duration = Duration::from_millis(500);
+pub const DEFAULT_MAX_RTO: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_ATTEMPTS: u8 = 4;
+
+/// A `UdpSocket` that retransmits `Type::Con` packets until they are
+/// acknowledged.
+///
+/// Received `Type::Con` packets are answered with an empty `Type::Ack`
+/// carrying the same message id. Retransmission only happens from `tick`,
+/// which the caller is expected to drive periodically. Datagrams are
+/// framed with a `PacketCodec`, `RawPacketCodec` by default.
+pub struct ReliableUdpSocket<C: PacketCodec = RawPacketCodec> {
+    socket: UdpSocket,
+    codec: C,
+    pending: HashMap<u16, Pending>,
+    rto: Duration,
+    max_rto: Duration,
+    max_attempts: u8,
+    on_timeout: Option<Box<dyn Fn(u16)>>,
+    on_reset: Option<Box<dyn Fn(u16)>>
+}
+
+struct Pending {
+    addr: SocketAddr,
+    packet: Packet,
+    last_sent: Instant,
+    attempts: u8
+}
+
+impl ReliableUdpSocket {
+    pub fn new(socket: UdpSocket) -> Self {
+        ReliableUdpSocket::with_codec(socket, RawPacketCodec::default())
+    }
+}
+
+impl<C: PacketCodec> ReliableUdpSocket<C> {
+    pub fn with_codec(socket: UdpSocket, codec: C) -> Self {
+        ReliableUdpSocket {
+            socket,
+            codec,
+            pending: HashMap::new(),
+            rto: DEFAULT_RTO,
+            max_rto: DEFAULT_MAX_RTO,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            on_timeout: None,
+            on_reset: None
+        }
+    }
+
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    pub fn codec_mut(&mut self) -> &mut C {
+        &mut self.codec
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Initial retransmission timeout, doubled after every attempt
+    pub fn set_rto(&mut self, rto: Duration) {
+        self.rto = rto;
+    }
+
+    /// Upper bound of the doubled retransmission timeout
+    pub fn set_max_rto(&mut self, max_rto: Duration) {
+        self.max_rto = max_rto;
+    }
+
+    pub fn set_max_attempts(&mut self, max_attempts: u8) {
+        self.max_attempts = max_attempts;
+    }
+
+    pub fn set_on_timeout(&mut self, f: impl Fn(u16) + 'static) {
+        self.on_timeout = Some(Box::new(f));
+    }
+
+    pub fn set_on_reset(&mut self, f: impl Fn(u16) + 'static) {
+        self.on_reset = Some(Box::new(f));
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn send_to(&mut self, packet: Packet, addr: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(&self.codec.encode(&packet)?, addr)?;
+
+        if packet.header.r#type()? == Type::Con {
+            self.pending.insert(packet.header.message_id(), Pending {
+                addr,
+                packet,
+                last_sent: Instant::now(),
+                attempts: 1
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn recv_from(&mut self) -> io::Result<(Packet, SocketAddr)> {
+        // the codec decides how large a packet may be
+        let mut buf = vec![0u8; u16::MAX as usize];
+        let (size, addr) = self.socket.recv_from(&mut buf)?;
+
+        let packet = self.codec.decode(&buf[..size])?;
+        let message_id = packet.header.message_id();
+
+        match packet.header.r#type()? {
+            Type::Con => {
+                let mut ack = Packet::new();
+                ack.header.set_message_id(message_id);
+                ack.header.set_type(Type::Ack);
+                ack.chan = packet.chan.clone();
+
+                self.socket.send_to(&self.codec.encode(&ack)?, addr)?;
+            }
+            Type::Ack => {
+                self.pending.remove(&message_id);
+            }
+            Type::Rst => {
+                if self.pending.remove(&message_id).is_some() {
+                    if let Some(on_reset) = &self.on_reset {
+                        on_reset(message_id);
+                    }
+                }
+            }
+            Type::Non => ()
+        }
+
+        Ok((packet, addr))
+    }
+
+    /// Retransmits and times out pending packets. Every pending packet is
+    /// processed even if a retransmission fails; the first error is returned
+    /// afterwards.
+    pub fn tick(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+
+        let mut timeout = Vec::new();
+        let mut error = None;
+
+        for (message_id, pending) in self.pending.iter_mut() {
+            let rto = rto(self.rto, pending.attempts, self.max_rto);
+
+            if now < pending.last_sent + rto {
+                continue
+            }
+
+            if pending.attempts >= self.max_attempts {
+                timeout.push(*message_id);
+                continue
+            }
+
+            // a failed send still counts as an attempt, so it is not retried in a loop
+            let socket = &self.socket;
+            let ret = self.codec.encode(&pending.packet)
+                .and_then(|bytes| socket.send_to(&bytes, pending.addr));
+
+            if let Err(err) = ret {
+                error.get_or_insert(err);
+            }
+
+            pending.last_sent = now;
+            pending.attempts += 1;
+        }
+
+        for message_id in timeout {
+            self.pending.remove(&message_id);
+
+            if let Some(on_timeout) = &self.on_timeout {
+                on_timeout(message_id);
+            }
+        }
+
+        match error {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+}
+
+// `base` doubled for every attempt after the first, capped at `max`
+fn rto(base: Duration, attempts: u8, max: Duration) -> Duration {
+    let factor = 2u32.checked_pow(u32::from(attempts.saturating_sub(1))).unwrap_or(u32::MAX);
+
+    base.checked_mul(factor).unwrap_or(max).min(max)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::net::UdpSocket;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::packet::{Packet, Type, test_packet};
+
+    use super::{ReliableUdpSocket, Pending, rto};
+
+    fn socket() -> ReliableUdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        ReliableUdpSocket::new(socket)
+    }
+
+    #[test]
+    fn con_is_acked() {
+        let mut a = socket();
+        let mut b = socket();
+        let b_addr = b.socket().local_addr().unwrap();
+
+        a.send_to(test_packet(1, Type::Con, "test", b"hello"), b_addr).unwrap();
+        assert_eq!(a.pending(), 1);
+
+        let (recv, _) = b.recv_from().unwrap();
+        assert_eq!(recv, test_packet(1, Type::Con, "test", b"hello"));
+
+        let (ack, _) = a.recv_from().unwrap();
+        assert_eq!(ack.header.r#type().unwrap(), Type::Ack);
+        assert_eq!(a.pending(), 0);
+    }
+
+    #[test]
+    fn retransmit_and_timeout() {
+        let mut a = socket();
+        a.set_rto(Duration::from_millis(5));
+        a.set_max_attempts(3);
+
+        let timeout = Rc::new(Cell::new(None));
+        let timeout2 = timeout.clone();
+        a.set_on_timeout(move |message_id| timeout2.set(Some(message_id)));
+
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        b.set_nonblocking(true).unwrap();
+
+        a.send_to(test_packet(2, Type::Con, "test", b"hello"), b.local_addr().unwrap()).unwrap();
+
+        while timeout.get().is_none() {
+            thread::sleep(Duration::from_millis(5));
+            a.tick().unwrap();
+        }
+
+        let mut buf = [0u8; 64];
+        let mut received = 0;
+        while let Ok((size, _)) = b.recv_from(&mut buf) {
+            assert_eq!(Packet::from_bytes(&buf[..size], false, None).unwrap(), test_packet(2, Type::Con, "test", b"hello"));
+            received += 1;
+        }
+
+        assert_eq!(received, 3);
+        assert_eq!(timeout.get(), Some(2));
+        assert_eq!(a.pending(), 0);
+    }
+
+    #[test]
+    fn rst_removes_pending() {
+        let mut a = socket();
+        let a_addr = a.socket().local_addr().unwrap();
+
+        let reset = Rc::new(Cell::new(None));
+        let reset2 = reset.clone();
+        a.set_on_reset(move |message_id| reset2.set(Some(message_id)));
+
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        a.send_to(test_packet(3, Type::Con, "test", b"hello"), b.local_addr().unwrap()).unwrap();
+        assert_eq!(a.pending(), 1);
+
+        b.send_to(&test_packet(3, Type::Rst, "test", b"hello").to_bytes(None).unwrap(), a_addr).unwrap();
+        a.recv_from().unwrap();
+
+        assert_eq!(reset.get(), Some(3));
+        assert_eq!(a.pending(), 0);
+    }
+
+    #[test]
+    fn rto_is_capped() {
+        let base = Duration::from_millis(10);
+        let max = Duration::from_secs(1);
+
+        assert_eq!(rto(base, 1, max), base);
+        assert_eq!(rto(base, 4, max), Duration::from_millis(80));
+        assert_eq!(rto(base, 40, max), max);
+        assert_eq!(rto(base, u8::MAX, max), max);
+        assert_eq!(rto(Duration::from_millis(0), u8::MAX, max), Duration::from_millis(0));
+    }
+
+    #[test]
+    fn many_attempts() {
+        let mut a = socket();
+        a.set_rto(Duration::from_millis(0));
+        a.set_max_attempts(40);
+
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.send_to(test_packet(4, Type::Con, "test", b"hello"), b.local_addr().unwrap()).unwrap();
+
+        for _ in 0..40 {
+            a.tick().unwrap();
+        }
+
+        assert_eq!(a.pending(), 0);
+    }
+
+    #[test]
+    fn send_error_does_not_skip_timeouts() {
+        let mut a = socket();
+        a.set_rto(Duration::from_millis(0));
+        a.set_max_attempts(2);
+
+        let timeout = Rc::new(Cell::new(None));
+        let timeout2 = timeout.clone();
+        a.set_on_timeout(move |message_id| timeout2.set(Some(message_id)));
+
+        // sending to port 0 fails
+        for (message_id, attempts) in [(5, 1), (6, 2)].iter() {
+            a.pending.insert(*message_id, Pending {
+                addr: "127.0.0.1:0".parse().unwrap(),
+                packet: test_packet(*message_id, Type::Con, "test", b"hello"),
+                last_sent: Instant::now(),
+                attempts: *attempts
+            });
+        }
+
+        assert!(a.tick().is_err());
+        assert_eq!(timeout.get(), Some(6));
+        assert_eq!(a.pending(), 1);
+    }
+}
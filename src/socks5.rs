@@ -0,0 +1,390 @@
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::thread;
+use std::time::Duration;
+
+use queen_io::net::tcp::TcpStream;
+use queen_io::queue::mpsc::Queue;
+
+use queen::Wire;
+use queen::crypto::Crypto;
+use queen::error::{Result, Error, Code};
+use queen::net::{NetWork, Packet, Codec, NsonCodec, CryptoOptions, KeepAlive};
+use queen::nson::{Message, MessageId};
+use queen::dict::{CHAN, CODE, HAND, ADDR, SECURE, SLOT_ID, ROOT, METHOD};
+use queen::util::message::read_block;
+
+use crate::conn::Connector;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE: u8 = 0xff;
+const USER_PASS_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Connects to `target_addr` through the SOCKS5 proxy at `proxy_addr`
+/// (RFC 1928), authenticating with username and password (RFC 1929) when
+/// `credentials` are given.
+///
+/// `Port::connect` dials the address itself, so the connector runs its own
+/// network thread, like a `Port`, and hands it the tunnelled stream once the
+/// handshake is done. The thread stops when the connector is dropped.
+pub struct Socks5Connector<C: Codec = NsonCodec> {
+    pub proxy_addr: SocketAddr,
+    pub target_addr: SocketAddr,
+    pub credentials: Option<(String, String)>,
+    pub slot_id: MessageId,
+    pub root: bool,
+    pub attr: Message,
+    pub crypto_options: Option<CryptoOptions>,
+    queue: Queue<Packet<C>>
+}
+
+impl<C: Codec> Socks5Connector<C> {
+    pub fn new(
+        proxy_addr: SocketAddr,
+        target_addr: SocketAddr,
+        credentials: Option<(String, String)>,
+        keep_alive: KeepAlive
+    ) -> Result<Self> {
+        let queue = Queue::new()?;
+
+        let mut net_work = NetWork::<C>::new(queue.clone(), keep_alive)?;
+
+        thread::Builder::new().name("socks5_net".to_string()).spawn(move || {
+            let ret = net_work.run();
+            if ret.is_err() {
+                log::error!("socks5 net thread exit: {:?}", ret);
+            } else {
+                log::debug!("socks5 net thread exit");
+            }
+        })?;
+
+        Ok(Socks5Connector {
+            proxy_addr,
+            target_addr,
+            credentials,
+            slot_id: MessageId::new(),
+            root: false,
+            attr: Message::new(),
+            crypto_options: None,
+            queue
+        })
+    }
+}
+
+impl<C: Codec> Connector for Socks5Connector<C> {
+    fn connect(&self) -> Result<Wire<Message>> {
+        log::debug!("socks5_connector::connect, proxy: {}, target: {}", self.proxy_addr, self.target_addr);
+
+        let mut stream = net::TcpStream::connect(self.proxy_addr)?;
+
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+
+        let credentials = self.credentials.as_ref().map(|(username, password)| (username.as_str(), password.as_str()));
+
+        handshake(&mut stream, self.target_addr, credentials)?;
+
+        // the queen handshake, as in `Port::connect`
+        let mut attr = self.attr.clone();
+        attr.insert(CHAN, HAND);
+        attr.insert(ADDR, self.target_addr.to_string());
+        attr.insert(SECURE, false);
+        attr.insert(SLOT_ID, self.slot_id);
+        attr.insert(ROOT, self.root);
+
+        let crypto = self.crypto_options.as_ref().map(|options| {
+            attr.insert(SECURE, true);
+            attr.insert(METHOD, options.method.as_str());
+
+            Crypto::new(&options.method, options.secret.as_bytes())
+        });
+
+        let mut codec = C::new();
+
+        let bytes = codec.encode(&None, attr)?;
+
+        stream.write_all(&bytes)?;
+
+        let bytes = read_block(&mut stream, Some(1024))?;
+        let mut message = codec.decode(&None, bytes)?;
+
+        match Code::get(&message) {
+            Some(Code::Ok) => {
+                message.remove(CHAN);
+                message.remove(CODE);
+
+                stream.set_read_timeout(None)?;
+                stream.set_write_timeout(None)?;
+
+                let stream = TcpStream::from_stream(stream);
+                stream.set_nonblocking(true)?;
+
+                let (wire1, wire2) = Wire::pipe(64, message)?;
+
+                self.queue.push(Packet::NewConn {
+                    wire: wire1,
+                    stream,
+                    codec,
+                    crypto
+                });
+
+                Ok(wire2)
+            }
+            Some(code) => Err(Error::ErrorCode(code)),
+            None => Err(Error::InvalidData(format!("{}", message)))
+        }
+    }
+}
+
+impl<C: Codec> Drop for Socks5Connector<C> {
+    fn drop(&mut self) {
+        self.queue.push(Packet::Close);
+    }
+}
+
+/// Runs the SOCKS5 method negotiation, optional username/password
+/// authentication and CONNECT request for `target` on `stream`.
+pub fn handshake(
+    stream: &mut (impl Read + Write),
+    target: SocketAddr,
+    credentials: Option<(&str, &str)>
+) -> io::Result<()> {
+    // method negotiation
+    if credentials.is_some() {
+        stream.write_all(&[VERSION, 2, NO_AUTH, USER_PASS])?;
+    } else {
+        stream.write_all(&[VERSION, 1, NO_AUTH])?;
+    }
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+
+    if reply[0] != VERSION {
+        return Err(invalid_data(format!("unexpected socks version: {}", reply[0])))
+    }
+
+    match (reply[1], credentials) {
+        (NO_AUTH, _) => (),
+        (USER_PASS, Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "socks credentials too long"))
+            }
+
+            let mut buf = vec![USER_PASS_VERSION, username.len() as u8];
+            buf.extend_from_slice(username.as_bytes());
+            buf.push(password.len() as u8);
+            buf.extend_from_slice(password.as_bytes());
+            stream.write_all(&buf)?;
+
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply)?;
+
+            if reply[1] != 0 {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "socks authentication failed"))
+            }
+        }
+        (NO_ACCEPTABLE, _) => {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "no acceptable socks auth method"))
+        }
+        (method, _) => {
+            return Err(invalid_data(format!("unexpected socks auth method: {}", method)))
+        }
+    }
+
+    // connect request
+    let mut buf = vec![VERSION, CMD_CONNECT, 0];
+    match target {
+        SocketAddr::V4(addr) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    buf.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&buf)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+
+    if reply[0] != VERSION {
+        return Err(invalid_data(format!("unexpected socks version: {}", reply[0])))
+    }
+
+    if reply[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("socks connect failed: {}", reply[1])))
+    }
+
+    // skip the bound address and port
+    let len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        atyp => return Err(invalid_data(format!("unexpected socks address type: {}", atyp)))
+    };
+
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound)?;
+
+    Ok(())
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream, SocketAddr, Shutdown};
+    use std::thread;
+    use std::time::Duration;
+
+    use queen::net::KeepAlive;
+    use queen::nson::msg;
+
+    use crate::conn::{Conn, Connector, hand_server};
+
+    use super::Socks5Connector;
+
+    // a minimal proxy that checks the handshake, then relays the tunnel to
+    // whatever `target` the client asked for
+    fn proxy(credentials: Option<(&'static str, &'static str)>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[0], 5);
+            let mut methods = vec![0u8; buf[1] as usize];
+            stream.read_exact(&mut methods).unwrap();
+
+            if let Some((username, password)) = credentials {
+                if !methods.contains(&2) {
+                    stream.write_all(&[5, 0xff]).unwrap();
+                    return
+                }
+
+                stream.write_all(&[5, 2]).unwrap();
+
+                let mut buf = [0u8; 2];
+                stream.read_exact(&mut buf).unwrap();
+                let mut user = vec![0u8; buf[1] as usize];
+                stream.read_exact(&mut user).unwrap();
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).unwrap();
+                let mut pass = vec![0u8; len[0] as usize];
+                stream.read_exact(&mut pass).unwrap();
+
+                if user != username.as_bytes() || pass != password.as_bytes() {
+                    stream.write_all(&[1, 1]).unwrap();
+                    return
+                }
+
+                stream.write_all(&[1, 0]).unwrap();
+            } else {
+                stream.write_all(&[5, 0]).unwrap();
+            }
+
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request[..4], [5, 1, 0, 1]);
+
+            let port = u16::from_be_bytes([request[8], request[9]]);
+            let target = match TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))) {
+                Ok(target) => target,
+                Err(_) => {
+                    // connection refused
+                    stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+                    return
+                }
+            };
+
+            stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+
+            relay(stream, target);
+        });
+
+        addr
+    }
+
+    fn relay(client: TcpStream, target: TcpStream) {
+        let mut client2 = client.try_clone().unwrap();
+        let mut target2 = target.try_clone().unwrap();
+
+        thread::spawn(move || {
+            let _ = io::copy(&mut { target }, &mut client2);
+            let _ = client2.shutdown(Shutdown::Write);
+        });
+
+        let _ = io::copy(&mut { client }, &mut target2);
+        let _ = target2.shutdown(Shutdown::Write);
+    }
+
+    fn connector(
+        proxy_addr: SocketAddr,
+        target_addr: SocketAddr,
+        credentials: Option<(&str, &str)>
+    ) -> Socks5Connector {
+        let credentials = credentials.map(|(username, password)| (username.to_string(), password.to_string()));
+
+        Socks5Connector::new(proxy_addr, target_addr, credentials, KeepAlive::default()).unwrap()
+    }
+
+    fn closed_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn no_auth() {
+        let connector = connector(proxy(None), hand_server(), None);
+        let wire = connector.connect().unwrap();
+
+        wire.send(msg!{"n": 1}).unwrap();
+        assert_eq!(wire.wait(Some(Duration::from_secs(5))).unwrap().get_i32("n").unwrap(), 1);
+    }
+
+    #[test]
+    fn user_pass() {
+        let proxy = proxy(Some(("user", "pass")));
+
+        let mut conn = Conn::new(connector(proxy, hand_server(), Some(("user", "pass"))));
+        conn.connect().unwrap();
+
+        conn.send(msg!{"n": 1}).unwrap();
+        assert_eq!(conn.wait(Some(Duration::from_secs(5))).unwrap().unwrap().get_i32("n").unwrap(), 1);
+    }
+
+    #[test]
+    fn user_pass_rejected() {
+        let proxy = proxy(Some(("user", "pass")));
+        assert!(connector(proxy, hand_server(), Some(("user", "wrong"))).connect().is_err());
+    }
+
+    #[test]
+    fn auth_required() {
+        let proxy = proxy(Some(("user", "pass")));
+        assert!(connector(proxy, hand_server(), None).connect().is_err());
+    }
+
+    #[test]
+    fn target_refused() {
+        assert!(connector(proxy(None), closed_addr(), None).connect().is_err());
+    }
+}
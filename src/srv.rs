@@ -0,0 +1,476 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use trust_dns_resolver::Resolver;
+
+use queen::{Wire, Port};
+use queen::error::{Result, Error};
+use queen::net::{Codec, NsonCodec, CryptoOptions};
+use queen::nson::{Message, MessageId};
+
+use crate::conn::Connector;
+
+/// Lower bound between two background refreshes
+const MIN_REFRESH: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub priority: u16,
+    pub weight: u16,
+    pub addr: SocketAddr
+}
+
+/// Connects to the endpoints published in a DNS SRV record, such as
+/// `_queen._tcp.service.local`.
+///
+/// Endpoints are tried by ascending priority, and by weighted random order
+/// within a priority (RFC 2782), falling back to the next one when a
+/// connection fails. Records are cached for their TTL and refreshed by a
+/// background thread that exits once the connector is dropped.
+pub struct SrvConnector<C: Codec = NsonCodec> {
+    pub port: Port<C>,
+    pub slot_id: MessageId,
+    pub root: bool,
+    pub attr: Message,
+    pub crypto_options: Option<CryptoOptions>,
+    name: String,
+    lookup: Arc<dyn Lookup>,
+    endpoints: Arc<Mutex<Vec<Endpoint>>>
+}
+
+impl<C: Codec> SrvConnector<C> {
+    pub fn new(
+        port: Port<C>,
+        name: impl Into<String>,
+        slot_id: MessageId,
+        root: bool,
+        attr: Message,
+        crypto_options: Option<CryptoOptions>
+    ) -> Result<Self> {
+        let resolver = Resolver::from_system_conf().map_err(|err|
+            Error::Disconnected(format!("srv resolver: {}", err))
+        )?;
+
+        Self::with_lookup(port, name, Arc::new(resolver), slot_id, root, attr, crypto_options)
+    }
+
+    fn with_lookup(
+        port: Port<C>,
+        name: impl Into<String>,
+        lookup: Arc<dyn Lookup>,
+        slot_id: MessageId,
+        root: bool,
+        attr: Message,
+        crypto_options: Option<CryptoOptions>
+    ) -> Result<Self> {
+        let name = name.into();
+
+        let (endpoints, valid_until) = resolve(&*lookup, &name)?;
+        let endpoints = Arc::new(Mutex::new(endpoints));
+
+        spawn_refresh(lookup.clone(), name.clone(), Arc::downgrade(&endpoints), valid_until)?;
+
+        Ok(SrvConnector {
+            port,
+            slot_id,
+            root,
+            attr,
+            crypto_options,
+            name,
+            lookup,
+            endpoints
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        self.endpoints.lock().unwrap().clone()
+    }
+}
+
+impl<C: Codec> Connector for SrvConnector<C> {
+    fn connect(&self) -> Result<Wire<Message>> {
+        let mut endpoints = self.endpoints();
+
+        if endpoints.is_empty() {
+            let (resolved, _) = resolve(&*self.lookup, &self.name)?;
+            *self.endpoints.lock().unwrap() = resolved.clone();
+            endpoints = resolved;
+        }
+
+        let addrs = order(endpoints, &mut rand::thread_rng());
+
+        failover(addrs, &self.name, |addr| {
+            self.port.connect(
+                addr,
+                self.slot_id,
+                self.root,
+                self.attr.clone(),
+                self.crypto_options.clone(),
+                None
+            )
+        })
+    }
+}
+
+/// Tries `addrs` in turn, returning the first successful connection or the
+/// last error.
+fn failover<T>(
+    addrs: Vec<SocketAddr>,
+    name: &str,
+    mut connect: impl FnMut(SocketAddr) -> Result<T>
+) -> Result<T> {
+    let mut last_err = None;
+
+    for addr in addrs {
+        log::debug!("srv_connector::connect, addr: {}", addr);
+
+        match connect(addr) {
+            Ok(wire) => return Ok(wire),
+            Err(err) => last_err = Some(err)
+        }
+    }
+
+    Err(last_err.unwrap_or_else(||
+        Error::Disconnected(format!("srv_connector::connect: no endpoints for {}", name))
+    ))
+}
+
+/// A SRV record: priority, weight, port and target host.
+type SrvRecord = (u16, u16, u16, String);
+
+/// The DNS queries `SrvConnector` needs, so they can be stubbed in tests.
+trait Lookup: Send + Sync + 'static {
+    /// SRV records for `name`, and when they expire
+    fn srv(&self, name: &str) -> Result<(Vec<SrvRecord>, Instant)>;
+
+    fn ip(&self, host: &str) -> Result<Vec<IpAddr>>;
+}
+
+impl Lookup for Resolver {
+    fn srv(&self, name: &str) -> Result<(Vec<SrvRecord>, Instant)> {
+        let lookup = self.srv_lookup(name).map_err(|err|
+            Error::Disconnected(format!("srv lookup {}: {}", name, err))
+        )?;
+
+        let records = lookup.iter()
+            .map(|srv| (srv.priority(), srv.weight(), srv.port(), srv.target().to_utf8()))
+            .collect();
+
+        Ok((records, lookup.as_lookup().valid_until()))
+    }
+
+    fn ip(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let ips = self.lookup_ip(host).map_err(|err|
+            Error::Disconnected(format!("ip lookup {}: {}", host, err))
+        )?;
+
+        Ok(ips.iter().collect())
+    }
+}
+
+fn resolve(lookup: &dyn Lookup, name: &str) -> Result<(Vec<Endpoint>, Instant)> {
+    let (records, valid_until) = lookup.srv(name)?;
+
+    let mut endpoints = Vec::new();
+
+    for (priority, weight, port, target) in records {
+        let ips = match lookup.ip(&target) {
+            Ok(ips) => ips,
+            Err(err) => {
+                log::warn!("srv_connector::resolve, target: {}, err: {}", target, err);
+                continue
+            }
+        };
+
+        for ip in ips {
+            endpoints.push(Endpoint {
+                priority,
+                weight,
+                addr: SocketAddr::new(ip, port)
+            });
+        }
+    }
+
+    Ok((endpoints, valid_until))
+}
+
+fn spawn_refresh(
+    lookup: Arc<dyn Lookup>,
+    name: String,
+    endpoints: Weak<Mutex<Vec<Endpoint>>>,
+    mut valid_until: Instant
+) -> Result<()> {
+    thread::Builder::new().name("srv_refresh".to_string()).spawn(move || {
+        loop {
+            let now = Instant::now();
+            thread::sleep(valid_until.saturating_duration_since(now).max(MIN_REFRESH));
+
+            let endpoints = match endpoints.upgrade() {
+                Some(endpoints) => endpoints,
+                None => return
+            };
+
+            match resolve(&*lookup, &name) {
+                Ok((resolved, until)) => {
+                    *endpoints.lock().unwrap() = resolved;
+                    valid_until = until;
+                }
+                Err(err) => {
+                    // keep the stale records and retry shortly
+                    log::warn!("srv_connector::refresh, name: {}, err: {}", name, err);
+                    valid_until = Instant::now() + MIN_REFRESH;
+                }
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Orders endpoints for connection attempts: ascending priority, then
+/// weighted random order within each priority.
+fn order(mut endpoints: Vec<Endpoint>, rng: &mut impl Rng) -> Vec<SocketAddr> {
+    endpoints.sort_by_key(|e| e.priority);
+
+    let mut ordered = Vec::with_capacity(endpoints.len());
+
+    while !endpoints.is_empty() {
+        let priority = endpoints[0].priority;
+        let end = endpoints.iter().position(|e| e.priority != priority).unwrap_or(endpoints.len());
+        let mut group: Vec<Endpoint> = endpoints.drain(..end).collect();
+
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|e| e.weight as u32).sum();
+
+            let index = if total == 0 {
+                0
+            } else {
+                let mut pick = rng.gen_range(0..total);
+                group.iter().position(|e| {
+                    if pick < e.weight as u32 {
+                        return true
+                    }
+
+                    pick -= e.weight as u32;
+                    false
+                }).unwrap_or(0)
+            };
+
+            ordered.push(group.remove(index).addr);
+        }
+    }
+
+    ordered
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use queen::Port;
+    use queen::error::{Result, Error};
+    use queen::net::{NsonCodec, KeepAlive};
+    use queen::nson::{Message, MessageId, msg};
+
+    use crate::conn::{Connector, hand_server};
+
+    use super::{SrvConnector, Endpoint, Lookup, SrvRecord, order, failover, resolve};
+
+    // answers SRV queries from `records`, valid for `ttl`
+    struct StubLookup {
+        records: Mutex<Vec<SrvRecord>>,
+        ttl: Duration,
+        calls: AtomicUsize
+    }
+
+    impl StubLookup {
+        fn new(records: Vec<SrvRecord>, ttl: Duration) -> Arc<Self> {
+            Arc::new(StubLookup {
+                records: Mutex::new(records),
+                ttl,
+                calls: AtomicUsize::new(0)
+            })
+        }
+    }
+
+    impl Lookup for StubLookup {
+        fn srv(&self, _name: &str) -> Result<(Vec<SrvRecord>, Instant)> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            Ok((self.records.lock().unwrap().clone(), Instant::now() + self.ttl))
+        }
+
+        fn ip(&self, host: &str) -> Result<Vec<IpAddr>> {
+            match host {
+                "a.local." => Ok(vec![[127, 0, 0, 1].into()]),
+                "b.local." => Ok(vec![[127, 0, 0, 2].into(), [127, 0, 0, 3].into()]),
+                _ => Err(Error::NotFound(host.to_string()))
+            }
+        }
+    }
+
+    fn record(priority: u16, weight: u16, port: u16, target: &str) -> SrvRecord {
+        (priority, weight, port, target.to_string())
+    }
+
+    fn connector(lookup: Arc<StubLookup>) -> SrvConnector<NsonCodec> {
+        let port = Port::new(KeepAlive::default()).unwrap();
+
+        SrvConnector::with_lookup(port, "_queen._tcp.test.", lookup, MessageId::new(), false, Message::new(), None).unwrap()
+    }
+
+    fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn endpoint(priority: u16, weight: u16, port: u16) -> Endpoint {
+        Endpoint {
+            priority,
+            weight,
+            addr: SocketAddr::from(([127, 0, 0, 1], port))
+        }
+    }
+
+    #[test]
+    fn order_by_priority() {
+        let endpoints = vec![
+            endpoint(20, 0, 3),
+            endpoint(10, 5, 1),
+            endpoint(30, 0, 4),
+            endpoint(10, 5, 2)
+        ];
+
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..100 {
+            let ordered = order(endpoints.clone(), &mut rng);
+            let ports: Vec<u16> = ordered.iter().map(|a| a.port()).collect();
+
+            assert_eq!(ports.len(), 4);
+            assert!(ports[..2].contains(&1) && ports[..2].contains(&2));
+            assert_eq!(ports[2..], [3, 4]);
+        }
+    }
+
+    #[test]
+    fn order_by_weight() {
+        let endpoints = vec![endpoint(0, 90, 1), endpoint(0, 10, 2)];
+
+        let mut rng = rand::thread_rng();
+
+        let first = (0..1000)
+            .filter(|_| order(endpoints.clone(), &mut rng)[0].port() == 1)
+            .count();
+
+        assert!(first > 800 && first < 980, "{}", first);
+    }
+
+    #[test]
+    fn failover_by_priority() {
+        let endpoints = vec![
+            endpoint(20, 0, 3),
+            endpoint(10, 5, 1),
+            endpoint(30, 0, 4),
+            endpoint(10, 5, 2)
+        ];
+
+        let mut rng = rand::thread_rng();
+
+        // both priority 10 endpoints are down
+        let mut tried = Vec::new();
+        let ret = failover(order(endpoints.clone(), &mut rng), "test", |addr| {
+            tried.push(addr.port());
+
+            if addr.port() <= 2 {
+                return Err(Error::Disconnected(format!("{} refused", addr)))
+            }
+
+            Ok(addr.port())
+        });
+
+        assert_eq!(ret.unwrap(), 3);
+        assert_eq!(tried.len(), 3);
+        assert!(tried[..2].contains(&1) && tried[..2].contains(&2));
+
+        // every endpoint is down: the last error is returned
+        let mut tried = Vec::new();
+        let ret: Result<()> = failover(order(endpoints, &mut rng), "test", |addr| {
+            tried.push(addr.port());
+            Err(Error::Disconnected(format!("{} refused", addr)))
+        });
+
+        assert_eq!(tried[2..], [3, 4]);
+        assert!(matches!(ret, Err(Error::Disconnected(msg)) if msg.starts_with("127.0.0.1:4")));
+
+        let ret: Result<()> = failover(Vec::new(), "test", |_| Ok(()));
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn resolve_every_target() {
+        let lookup = StubLookup::new(vec![
+            record(10, 5, 1, "a.local."),
+            record(20, 0, 2, "b.local."),
+            record(30, 0, 3, "missing.local.")
+        ], Duration::from_secs(60));
+
+        let (endpoints, _) = resolve(&*lookup, "test").unwrap();
+
+        assert_eq!(endpoints, vec![
+            Endpoint { priority: 10, weight: 5, addr: SocketAddr::from(([127, 0, 0, 1], 1)) },
+            Endpoint { priority: 20, weight: 0, addr: SocketAddr::from(([127, 0, 0, 2], 2)) },
+            Endpoint { priority: 20, weight: 0, addr: SocketAddr::from(([127, 0, 0, 3], 2)) }
+        ]);
+    }
+
+    #[test]
+    fn connect_from_cache() {
+        let lookup = StubLookup::new(vec![
+            record(10, 0, closed_port(), "a.local."),
+            record(20, 0, hand_server().port(), "a.local.")
+        ], Duration::from_secs(60));
+
+        let conn = connector(lookup.clone());
+        assert_eq!(conn.endpoints().len(), 2);
+
+        // the priority 10 endpoint refuses, the next one answers
+        let wire = conn.connect().unwrap();
+        wire.send(msg!{"n": 1}).unwrap();
+        assert_eq!(wire.wait(Some(Duration::from_secs(5))).unwrap().get_i32("n").unwrap(), 1);
+
+        assert_eq!(lookup.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn refresh_after_ttl() {
+        let lookup = StubLookup::new(vec![record(10, 0, 1, "a.local.")], Duration::from_millis(0));
+
+        let conn = connector(lookup.clone());
+        assert_eq!(conn.endpoints()[0].addr.port(), 1);
+
+        *lookup.records.lock().unwrap() = vec![record(10, 0, 2, "a.local.")];
+
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(conn.endpoints()[0].addr.port(), 2);
+        assert!(lookup.calls.load(Ordering::SeqCst) >= 2);
+
+        // the refresh thread exits once the connector is dropped
+        drop(conn);
+        thread::sleep(Duration::from_millis(1500));
+        let calls = lookup.calls.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(lookup.calls.load(Ordering::SeqCst), calls);
+        assert_eq!(Arc::strong_count(&lookup), 1);
+    }
+}